@@ -1,59 +1,182 @@
-use std::{collections::LinkedList, sync::Arc};
+use std::{
+    collections::LinkedList,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use axum::{
-    extract::Multipart,
-    routing::{any, post},
+    extract::{Multipart, Query},
+    routing::{any, get, post},
     Extension, Router,
 };
 use bytes::Bytes;
+use futures_util::TryStreamExt;
 use reqwest::{multipart::Part, StatusCode};
 
 use crate::{
-    config::{DownstreamConfig, FishTTS, LLMConfig, StableTTS, TTSConfig},
-    llm::{fish_tts, llm::Content, llm_stable, tts},
-    stream_platform::CommentTx,
+    config::{AsrConfig, DownstreamConfig, LLMConfig, PodcastPolicy, ShutdownConfig},
+    llm::{asr, llm::Content, llm_stable, ChatOptions},
+    metrics::METRICS,
+    recorder::{self, Record},
+    state::StateStore,
+    status::{ReplyProgress, Status},
+    stream_platform::{CommentRequest, PlatformEvent, SteamEvent},
+    supervisor::Supervisor,
+    tts::Tts,
 };
 
+/// Set to `true` once SIGINT or SIGTERM arrives.
+#[derive(Clone)]
+pub struct Shutdown {
+    pub signal: tokio::sync::watch::Receiver<bool>,
+    pub config: ShutdownConfig,
+}
+
+impl Shutdown {
+    /// Resolves once the shutdown started, never if the sender is gone without one.
+    async fn started(&mut self) {
+        if self.signal.wait_for(|s| *s).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    fn is_started(&self) -> bool {
+        *self.signal.borrow()
+    }
+}
+
+/// Returns the routes and the agent task, which ends after a shutdown.
 pub fn router(
     llm_config: LLMConfig,
-    tts_config: TTSConfig,
-    downstream_config: DownstreamConfig,
-    stream_tx: tokio::sync::mpsc::UnboundedSender<CommentTx>,
-) -> Router {
+    llm_agent: LlmAgent,
+    asr_config: Option<AsrConfig>,
+    state: Option<Arc<StateStore>>,
+    stream_tx: tokio::sync::mpsc::UnboundedSender<CommentRequest>,
+    shutdown: Shutdown,
+    supervisor: &Supervisor,
+) -> (Router, tokio::task::JoinHandle<()>) {
     let (store_tx, store_rx) = tokio::sync::mpsc::unbounded_channel();
     let (tx, rx) = tokio::sync::mpsc::channel(10);
-    let store = PodcastStore::new(store_tx);
+    let store = PodcastStore::new(store_tx, state.clone(), llm_agent.status.clone());
     tokio::spawn(store.run_loop(rx));
 
-    let callback_notify = Arc::new(tokio::sync::Notify::new());
+    let downstream_ = llm_agent.downstream.clone();
+    let status = llm_agent.status.clone();
+    let shutdown_signal = shutdown.signal.clone();
 
-    let downstream = Arc::new(Downstream {
-        update_title_url: downstream_config.update_title_url,
-        segment_url: downstream_config.segment_url,
-    });
-
-    let callback_notify_ = callback_notify.clone();
+    let (host_tx, host_rx) = tokio::sync::mpsc::unbounded_channel();
 
-    tokio::spawn(async {
-        let r = stream_handler(store_rx, downstream, stream_tx, llm_config, tts_config).await;
-        if let Err(e) = r {
-            log::error!("stream_handler error: {:?}", e);
+    // the receivers outlive a failed stream_handler, so nothing queued is lost on restart
+    let receivers = Arc::new(tokio::sync::Mutex::new((store_rx, host_rx)));
+    let handler = supervisor.spawn("stream_handler", move || {
+        let receivers = receivers.clone();
+        let stream_tx = stream_tx.clone();
+        let state = state.clone();
+        let llm_config = llm_config.clone();
+        let llm_agent = llm_agent.clone();
+        let shutdown = shutdown.clone();
+        async move {
+            let mut receivers = receivers.lock().await;
+            let (rx, host_rx) = &mut *receivers;
+            stream_handler(
+                rx, stream_tx, host_rx, state, llm_config, llm_agent, shutdown,
+            )
+            .await
         }
     });
 
-    Router::new()
+    let mut router = Router::new()
         .route("/send_msg_form", post(send_msg_form))
         .route("/callback", any(callback))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/status", get(status_report))
+        .route("/metrics", get(metrics));
+    if let Some(asr_config) = asr_config {
+        router = router
+            .route("/host_voice", post(host_voice))
+            .layer(Extension(Arc::new(asr_config)))
+            .layer(Extension(host_tx));
+    }
+
+    let router = router
         .layer(Extension(tx))
-        .layer(Extension(callback_notify_))
-        .layer(axum::extract::DefaultBodyLimit::max(10 * 1024 * 1024))
+        .layer(Extension(downstream_))
+        .layer(Extension(status))
+        .layer(Extension(supervisor.clone()))
+        .layer(Extension(shutdown_signal))
+        .layer(axum::extract::DefaultBodyLimit::max(10 * 1024 * 1024));
+    (router, handler)
 }
 
+fn report(
+    status: &Status,
+    supervisor: &Supervisor,
+    shutdown: &tokio::sync::watch::Receiver<bool>,
+) -> crate::status::Report {
+    let mut report = status.report();
+    report.tasks = supervisor.health();
+    report.shutting_down = *shutdown.borrow();
+    report
+}
+
+/// 503 once a background task was given up on.
+async fn healthz(
+    Extension(status): Extension<Arc<Status>>,
+    Extension(supervisor): Extension<Supervisor>,
+    Extension(shutdown): Extension<tokio::sync::watch::Receiver<bool>>,
+) -> (StatusCode, &'static str) {
+    if report(&status, &supervisor, &shutdown).is_healthy() {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unhealthy")
+    }
+}
+
+/// 503 while a platform or task is down, or shutting down.
+async fn readyz(
+    Extension(status): Extension<Arc<Status>>,
+    Extension(supervisor): Extension<Supervisor>,
+    Extension(shutdown): Extension<tokio::sync::watch::Receiver<bool>>,
+) -> (StatusCode, &'static str) {
+    if report(&status, &supervisor, &shutdown).is_ready() {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    }
+}
+
+async fn status_report(
+    Extension(status): Extension<Arc<Status>>,
+    Extension(supervisor): Extension<Supervisor>,
+    Extension(shutdown): Extension<tokio::sync::watch::Receiver<bool>>,
+) -> axum::Json<crate::status::Report> {
+    axum::Json(report(&status, &supervisor, &shutdown))
+}
+
+async fn metrics() -> Result<String, StatusCode> {
+    METRICS.encode().map_err(|e| {
+        log::error!("encode metrics failed: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CallbackQuery {
+    #[serde(default)]
+    id: Option<u64>,
+}
+
+/// The downstream finished playing segment `id`, or the oldest unfinished one without `id`.
 async fn callback(
-    Extension(callback_notify): Extension<Arc<tokio::sync::Notify>>,
+    Extension(downstream): Extension<Arc<Downstream>>,
+    Query(query): Query<CallbackQuery>,
 ) -> Result<String, StatusCode> {
-    log::info!("callback");
-    callback_notify.notify_waiters();
+    log::info!("callback {:?}", query.id);
+    downstream.playback.finish(query.id);
     Ok("ok".to_string())
 }
 
@@ -66,6 +189,9 @@ pub struct SendMsgRequest {
     motion: Option<String>,
     #[serde(skip)]
     voice: Option<Bytes>,
+    /// Audio format of `voice`, `wav` if `None`.
+    #[serde(skip)]
+    voice_format: Option<String>,
 }
 
 async fn parse_from_multipart(mut multipart: Multipart) -> anyhow::Result<SendMsgRequest> {
@@ -74,6 +200,7 @@ async fn parse_from_multipart(mut multipart: Multipart) -> anyhow::Result<SendMs
         text: None,
         motion: None,
         voice: None,
+        voice_format: None,
     };
 
     while let Some(field) = multipart.next_field().await? {
@@ -89,6 +216,10 @@ async fn parse_from_multipart(mut multipart: Multipart) -> anyhow::Result<SendMs
                 req.motion = Some(field.text().await?);
             }
             "voice" => {
+                req.voice_format = field
+                    .file_name()
+                    .and_then(|n| n.rsplit_once('.'))
+                    .map(|(_, ext)| ext.to_lowercase());
                 let data = field.bytes().await?;
                 if !data.is_empty() {
                     req.voice = Some(data);
@@ -112,7 +243,7 @@ async fn send_msg_form(
     }
 
     match tx.send(msg.unwrap()).await {
-        Ok(_) => Ok(format!("ok")),
+        Ok(_) => Ok("ok".to_string()),
         Err(e) => {
             log::error!("random_say error: {:?}", e);
             Err(StatusCode::INTERNAL_SERVER_ERROR)
@@ -120,6 +251,59 @@ async fn send_msg_form(
     }
 }
 
+/// Something the host said, transcribed from `/host_voice`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostUtterance {
+    pub name: String,
+    pub text: String,
+}
+
+type HostTx = tokio::sync::mpsc::UnboundedSender<HostUtterance>;
+type HostRx = tokio::sync::mpsc::UnboundedReceiver<HostUtterance>;
+
+/// multipart: `voice` wav audio, optional `name` of the (co-)host.
+/// Returns the transcription.
+async fn host_voice(
+    Extension(asr_config): Extension<Arc<AsrConfig>>,
+    Extension(host_tx): Extension<HostTx>,
+    mut multipart: Multipart,
+) -> Result<String, StatusCode> {
+    let mut name = asr_config.host_name.clone();
+    let mut voice = None;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?
+    {
+        match field.name().unwrap_or_default() {
+            "name" => name = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?,
+            "voice" => voice = Some(field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?),
+            _ => {}
+        }
+    }
+    let voice = voice.ok_or(StatusCode::BAD_REQUEST)?;
+
+    let text = asr(&asr_config.url, &asr_config.lang, voice.to_vec())
+        .await
+        .map_err(|e| {
+            log::error!("asr error: {:?}", e);
+            StatusCode::BAD_GATEWAY
+        })?
+        .join(" ");
+    log::info!("host {}: {}", name, text);
+    if text.is_empty() {
+        return Ok(text);
+    }
+
+    host_tx
+        .send(HostUtterance {
+            name,
+            text: text.clone(),
+        })
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(text)
+}
+
 type PodcastTx = tokio::sync::mpsc::Sender<SendMsgRequest>;
 type PodcastRx = tokio::sync::mpsc::Receiver<SendMsgRequest>;
 
@@ -130,15 +314,29 @@ pub struct Podcast {
 
 struct PodcastStore {
     store: tokio::sync::mpsc::UnboundedSender<Podcast>,
+    state: Option<Arc<StateStore>>,
+    status: Arc<Status>,
 }
 
 impl PodcastStore {
-    fn new(store: tokio::sync::mpsc::UnboundedSender<Podcast>) -> Self {
-        Self { store }
+    fn new(
+        store: tokio::sync::mpsc::UnboundedSender<Podcast>,
+        state: Option<Arc<StateStore>>,
+        status: Arc<Status>,
+    ) -> Self {
+        Self {
+            store,
+            state,
+            status,
+        }
     }
 
     async fn run_loop(self, mut rx: PodcastRx) -> anyhow::Result<()> {
-        let mut title_index = 0;
+        let mut title_index = self
+            .state
+            .as_ref()
+            .and_then(|s| s.resume())
+            .map_or(0, |s| s.title_index);
 
         loop {
             let mut podcast = Podcast {
@@ -146,7 +344,9 @@ impl PodcastStore {
                 segment: Vec::with_capacity(20),
             };
 
-            let segment = rx.recv().await.ok_or(anyhow::anyhow!("rx closed"))?;
+            let Some(segment) = rx.recv().await else {
+                return Ok(());
+            };
             podcast.segment.push(segment);
 
             loop {
@@ -157,14 +357,20 @@ impl PodcastStore {
                         podcast.segment.push(segment);
                     }
 
-                    Ok(None) => {
-                        Err(anyhow::anyhow!("rx closed"))?;
-                    }
-                    Err(_) => {
+                    // shutting down, hand over what we have
+                    Ok(None) | Err(_) => {
                         title_index += 1;
+                        if let Some(state) = &self.state {
+                            state.update(|s| s.title_index = title_index);
+                        }
                         self.store
                             .send(podcast)
                             .map_err(|_| anyhow::anyhow!("podcast out channel closed"))?;
+                        self.status.update(|s| s.queued_podcasts += 1);
+                        METRICS.podcast_queue.inc();
+                        if segment.is_ok() {
+                            return Ok(());
+                        }
                         break;
                     }
                 }
@@ -173,12 +379,79 @@ impl PodcastStore {
     }
 }
 
+/// Tracks which segments the downstream has played.
+///
+/// Segment ids increase by one per segment and are played in order, so a
+/// single watermark is enough.
+pub struct Playback {
+    last_id: AtomicU64,
+    done: tokio::sync::watch::Sender<u64>,
+    timeout: Duration,
+}
+
+impl Playback {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            last_id: AtomicU64::new(0),
+            done: tokio::sync::watch::Sender::new(0),
+            timeout,
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        self.last_id.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Id of the last played segment.
+    pub fn played(&self) -> u64 {
+        *self.done.borrow()
+    }
+
+    pub fn finish(&self, id: Option<u64>) {
+        let last_id = self.last_id.load(Ordering::SeqCst);
+        self.done.send_if_modified(|done| {
+            let id = id.unwrap_or(*done + 1).min(last_id);
+            if id > *done {
+                *done = id;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    /// Waits until segment `id` was played. A segment that is not called back
+    /// within the timeout is considered played.
+    pub async fn wait(&self, id: u64) {
+        if self.timeout.is_zero() {
+            return;
+        }
+        let mut done = self.done.subscribe();
+        let r = tokio::time::timeout(self.timeout, done.wait_for(|done| *done >= id)).await;
+        if r.is_err() {
+            log::warn!("segment {} playback timeout", id);
+            self.finish(Some(id));
+        }
+    }
+}
+
 pub struct Downstream {
     pub update_title_url: String,
     pub segment_url: String,
+    pub stop_url: Option<String>,
+    pub playback: Playback,
 }
 
 impl Downstream {
+    pub fn new(config: DownstreamConfig) -> Self {
+        Self {
+            update_title_url: config.update_title_url,
+            segment_url: config.segment_url,
+            stop_url: config.stop_url,
+            playback: Playback::new(Duration::from_secs(config.playback_timeout_secs)),
+        }
+    }
+
     pub async fn update_title(&self, title: String) -> anyhow::Result<()> {
         let client = reqwest::Client::new();
         let res = client
@@ -193,18 +466,36 @@ impl Downstream {
         }
     }
 
+    /// Sends a segment once the one before the previous has been played, so
+    /// the downstream holds at most the playing segment and the next one.
+    /// Returns the segment id the downstream calls `/callback?id=` with.
     pub async fn send_segment(
         &self,
         client: &reqwest::Client,
         segment: SendMsgRequest,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<u64> {
         let SendMsgRequest {
             vtb_name,
             text,
             motion,
             voice,
+            voice_format,
         } = segment;
-        let mut form = reqwest::multipart::Form::new().part("vtb_name", Part::text(vtb_name));
+        let voice = voice
+            .map(|voice| {
+                let format = voice_format.as_deref().unwrap_or("wav");
+                Part::stream(voice)
+                    .file_name(format!("audio.{format}"))
+                    .mime_str(crate::tts::content_type(format))
+            })
+            .transpose()?;
+        let id = self.playback.next_id();
+        self.playback.wait(id.saturating_sub(2)).await;
+        let recorded_text = text.clone();
+
+        let mut form = reqwest::multipart::Form::new()
+            .part("id", Part::text(id.to_string()))
+            .part("vtb_name", Part::text(vtb_name));
         if let Some(text) = text {
             form = form.part("text", Part::text(text));
         }
@@ -212,133 +503,360 @@ impl Downstream {
             form = form.part("motion", Part::text(motion));
         }
         if let Some(voice) = voice {
-            form = form.part("voice", Part::stream(voice).file_name("audio.wav"));
+            form = form.part("voice", voice);
         }
 
-        let res = client
-            .post(&self.segment_url)
-            .multipart(form)
-            .send()
-            .await?;
+        let res = client.post(&self.segment_url).multipart(form).send().await;
+        let r = match res {
+            Ok(res) if res.status().is_success() => Ok(id),
+            r => {
+                // it will never be called back
+                self.playback.finish(Some(id));
+                METRICS.downstream_failures.inc();
+                Err(anyhow::anyhow!("send segment {} failed: {:?}", id, r))
+            }
+        };
+        recorder::record(Record::Delivery {
+            id,
+            text: recorded_text,
+            error: r.as_ref().err().map(|e| e.to_string()),
+        });
+        r
+    }
+
+    /// Drops the playing and queued segments.
+    pub async fn stop(&self) -> anyhow::Result<()> {
+        // nothing sent so far will be called back
+        self.playback
+            .finish(Some(self.playback.last_id.load(Ordering::SeqCst)));
+        let Some(stop_url) = &self.stop_url else {
+            return Ok(());
+        };
+        let res = reqwest::Client::new().post(stop_url).send().await?;
         if res.status().is_success() {
             Ok(())
         } else {
-            Err(anyhow::anyhow!("send segment failed"))
+            Err(anyhow::anyhow!("stop failed"))
         }
     }
 
+    /// Sends the podcast and waits until it was played.
     pub async fn send(&self, podcast: Podcast) -> anyhow::Result<()> {
         let client = reqwest::Client::new();
         let Podcast { title, segment } = podcast;
         self.update_title(title).await?;
+        let mut last_id = None;
         for s in segment {
-            self.send_segment(&client, s).await?;
+            last_id = Some(self.send_segment(&client, s).await?);
+        }
+        if let Some(id) = last_id {
+            self.playback.wait(id).await;
         }
 
         Ok(())
     }
 }
 
-async fn get_comments(
-    stream_tx: &tokio::sync::mpsc::UnboundedSender<CommentTx>,
-) -> anyhow::Result<LinkedList<(String, String)>> {
-    let (tx, rx) = tokio::sync::oneshot::channel();
-    stream_tx
-        .send(tx)
-        .map_err(|_| anyhow::anyhow!("stream_tx closed"))?;
-    rx.await
-        .map_err(|_| anyhow::anyhow!("stream_tx closed on get_commonts"))
+/// Asks `llm_loop` for comments.
+///
+/// A request given up on, e.g. when another `select!` branch wins, is closed and
+/// whatever `llm_loop` already sent to it is held for the next call, so no batch
+/// is lost between the two.
+struct Comments {
+    stream_tx: tokio::sync::mpsc::UnboundedSender<CommentRequest>,
+    held: LinkedList<PlatformEvent>,
 }
 
-fn parse_comments(comments: LinkedList<(String, String)>) -> String {
+/// Takes back the batch of an abandoned request.
+struct PendingComments<'a> {
+    rx: crate::stream_platform::CommentRx,
+    held: &'a mut LinkedList<PlatformEvent>,
+}
+
+impl Drop for PendingComments<'_> {
+    fn drop(&mut self) {
+        // after `close` the send either already happened or fails in `llm_loop`
+        self.rx.close();
+        if let Ok(mut comments) = self.rx.try_recv() {
+            log::debug!("hold {} comments of a dropped request", comments.len());
+            self.held.append(&mut comments);
+        }
+    }
+}
+
+impl Comments {
+    fn new(stream_tx: tokio::sync::mpsc::UnboundedSender<CommentRequest>) -> Self {
+        Self {
+            stream_tx,
+            held: LinkedList::new(),
+        }
+    }
+
+    /// All stored events once one of them passes `wake`.
+    async fn get(
+        &mut self,
+        wake: fn(&SteamEvent) -> bool,
+    ) -> anyhow::Result<LinkedList<PlatformEvent>> {
+        if self.held.iter().any(|e| wake(&e.event)) {
+            return Ok(std::mem::take(&mut self.held));
+        }
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.stream_tx
+            .send(CommentRequest { tx, wake })
+            .map_err(|_| anyhow::anyhow!("stream_tx closed"))?;
+        let mut pending = PendingComments {
+            rx,
+            held: &mut self.held,
+        };
+        let mut comments = (&mut pending.rx)
+            .await
+            .map_err(|_| anyhow::anyhow!("stream_tx closed on get_commonts"))?;
+        let mut held = std::mem::take(pending.held);
+        held.append(&mut comments);
+        Ok(held)
+    }
+}
+
+fn parse_comments(comments: LinkedList<PlatformEvent>) -> String {
     let mut text = String::new();
     text.push_str("以下是用户的评论：\n");
-    for (user, content) in comments {
-        text.push_str(&format!("{}: {}\n", user, content));
+    let mut paid = false;
+    for event in comments {
+        paid |= event.event.is_paid();
+        text.push_str(&format!("{}\n", event));
+    }
+    if paid {
+        text.push_str("请先感谢送出礼物和付费留言的观众。\n");
     }
     text
 }
 
+#[derive(Clone)]
 pub struct LlmAgent {
     pub downstream: Arc<Downstream>,
-    pub tts_config: TTSConfig,
+    pub tts: Tts,
+    pub tts_concurrency: usize,
+    /// Segment ids and texts of the current reply sent to the downstream.
+    delivered: Vec<(u64, String)>,
+    pub status: Arc<Status>,
 }
 
 impl LlmAgent {
+    pub fn new(
+        downstream: Arc<Downstream>,
+        tts: Tts,
+        tts_concurrency: usize,
+        status: Arc<Status>,
+    ) -> Self {
+        Self {
+            downstream,
+            tts,
+            tts_concurrency,
+            delivered: vec![],
+            status,
+        }
+    }
+
+    /// Streams the LLM reply to the downstream.
+    ///
+    /// LLM streaming, TTS of up to `tts_concurrency` sentences and delivery to
+    /// the downstream run concurrently, segments are still delivered in order.
+    /// Returns once the downstream played the last segment.
     pub async fn reply<I: IntoIterator<Item = C>, C: AsRef<Content>>(
         &mut self,
         llm_url: &str,
         token: &str,
+        options: &ChatOptions,
         prompts: I,
     ) -> anyhow::Result<String> {
         let http_cli = reqwest::Client::new();
-        let mut resp = llm_stable(llm_url, token, None, prompts)
-            .await
-            .map_err(|e| anyhow::anyhow!("llm_stable error: {:?}", e))?;
-        let mut llm_reply = String::with_capacity(128);
-        while let Some(chunk) = resp
-            .next_chunk()
+        self.delivered.clear();
+        self.status.update(|s| {
+            s.reply = Some(ReplyProgress {
+                started_at: crate::util::now(),
+                ..Default::default()
+            })
+        });
+        let r = self
+            .stream_reply(&http_cli, llm_url, token, options, prompts)
+            .await;
+        self.status.update(|s| s.reply = None);
+        if let Err(e) = &r {
+            self.status.error("llm", e);
+        }
+        r
+    }
+
+    async fn stream_reply<I: IntoIterator<Item = C>, C: AsRef<Content>>(
+        &mut self,
+        http_cli: &reqwest::Client,
+        llm_url: &str,
+        token: &str,
+        options: &ChatOptions,
+        prompts: I,
+    ) -> anyhow::Result<String> {
+        let started = std::time::Instant::now();
+        let first_token_status = self.status.clone();
+        let resp = llm_stable(llm_url, token, None, options, prompts)
             .await
-            .map_err(|e| anyhow::anyhow!("llm_stable next_chunk error: {:?}", e))?
-        {
-            log::info!("start tts {}", chunk);
-            llm_reply.push_str(&chunk);
-            let (vtb_name, audio) = match &self.tts_config {
-                TTSConfig::Stable(StableTTS {
-                    base_url,
-                    speaker,
-                    vtb_name,
-                }) => (
-                    vtb_name.to_string(),
-                    tts(base_url, speaker, &chunk)
-                        .await
-                        .map_err(|e| anyhow::anyhow!("tts error: {:?}", e)),
-                ),
-                TTSConfig::Fish(FishTTS {
-                    api_key,
-                    speaker,
-                    vtb_name,
-                }) => (
-                    vtb_name.to_string(),
-                    fish_tts(api_key, speaker, &chunk)
-                        .await
-                        .map_err(|e| anyhow::anyhow!("tts error: {:?}", e)),
-                ),
-            };
-            log::info!("tts done");
+            .map_err(|e| anyhow::anyhow!("llm_stable error: {:?}", e))?
+            .on_first_token(move |latency| {
+                METRICS
+                    .llm_first_token_seconds
+                    .observe(latency.as_secs_f64());
+                first_token_status.update(|s| s.llm_latency_ms = Some(latency.as_millis() as u64));
+            });
 
-            if let Err(e) = &audio {
-                log::error!("tts failed: {:?}", e);
-            }
+        let depth = self.tts_concurrency.max(1);
+        let (segment_tx, mut segment_rx) = tokio::sync::mpsc::channel::<SendMsgRequest>(depth);
+        let tts = &self.tts;
+        let status = &self.status;
 
-            let voice = audio.ok();
+        let produce = async move {
+            let chunks = futures_util::stream::try_unfold(resp, |mut resp| async move {
+                let chunk = resp
+                    .next_chunk()
+                    .await
+                    .map_err(|e| anyhow::anyhow!("llm_stable next_chunk error: {:?}", e))?;
+                if chunk.is_none() {
+                    METRICS
+                        .llm_reply_seconds
+                        .observe(started.elapsed().as_secs_f64());
+                }
+                Ok::<_, anyhow::Error>(chunk.map(|c| (c, resp)))
+            });
 
-            if let Err(e) = self
-                .downstream
-                .send_segment(
-                    &http_cli,
-                    SendMsgRequest {
-                        vtb_name,
-                        text: Some(chunk),
-                        motion: None,
-                        voice,
-                    },
-                )
-                .await
-            {
-                log::error!("send_segment failed: {:?}", e);
+            let segments = chunks
+                .map_ok(|chunk| async move {
+                    log::info!("start tts {}", chunk);
+                    let tts_started = std::time::Instant::now();
+                    let audio = tts.synthesize(&chunk).await;
+                    log::info!("tts done");
+                    let latency = tts_started.elapsed();
+                    let provider = tts.engine.name();
+                    METRICS
+                        .tts_seconds
+                        .with_label_values(&[provider])
+                        .observe(latency.as_secs_f64());
+                    status.update(|s| s.tts_latency_ms = Some(latency.as_millis() as u64));
+                    let mut audio_secs = None;
+                    match &audio {
+                        Ok(audio) => {
+                            audio_secs = crate::tts::wav_duration(audio).map(|d| d.as_secs_f64());
+                            if let Some(secs) = audio_secs {
+                                METRICS
+                                    .tts_audio_seconds
+                                    .with_label_values(&[provider])
+                                    .inc_by(secs);
+                            }
+                        }
+                        Err(e) => {
+                            log::error!("{} tts failed: {:?}", provider, e);
+                            METRICS.tts_failures.with_label_values(&[provider]).inc();
+                            status.error(provider, e);
+                        }
+                    }
+                    recorder::record(Record::Tts {
+                        provider: provider.to_string(),
+                        text: chunk.clone(),
+                        latency_ms: latency.as_millis() as u64,
+                        audio_secs,
+                        error: audio.as_ref().err().map(|e| format!("{:#}", e)),
+                    });
+                    Ok((chunk, audio.ok()))
+                })
+                .try_buffered(depth);
+            let mut segments = std::pin::pin!(segments);
+
+            let mut llm_reply = String::with_capacity(128);
+            while let Some((chunk, voice)) = segments.try_next().await? {
+                llm_reply.push_str(&chunk);
+                let segment = SendMsgRequest {
+                    vtb_name: tts.vtb_name.clone(),
+                    text: Some(chunk),
+                    motion: None,
+                    voice,
+                    voice_format: Some(tts.engine.format().to_string()),
+                };
+                if segment_tx.send(segment).await.is_err() {
+                    break;
+                }
             }
+            anyhow::Ok(llm_reply)
+        };
+
+        let downstream = &self.downstream;
+        let delivered = &mut self.delivered;
+        let deliver = async move {
+            while let Some(segment) = segment_rx.recv().await {
+                let text = segment.text.clone().unwrap_or_default();
+                match downstream.send_segment(http_cli, segment).await {
+                    Ok(id) => {
+                        status.update(|s| {
+                            if let Some(reply) = &mut s.reply {
+                                reply.segments_delivered += 1;
+                                reply.text.push_str(&text);
+                            }
+                        });
+                        delivered.push((id, text));
+                    }
+                    Err(e) => {
+                        log::error!("send_segment failed: {:?}", e);
+                        status.error("downstream", &e);
+                    }
+                }
+            }
+            if let Some((id, _)) = delivered.last() {
+                downstream.playback.wait(*id).await;
+            }
+        };
+
+        let (llm_reply, _) = tokio::join!(produce, deliver);
+        llm_reply
+    }
+
+    /// Stops the downstream after `reply` was dropped midway, and returns
+    /// what the audience heard of it, ending with `……` where it was cut off.
+    pub async fn interrupt(&mut self) -> String {
+        self.status.update(|s| s.reply = None);
+        let played = self.downstream.playback.played();
+        if let Err(e) = self.downstream.stop().await {
+            log::error!("stop downstream failed: {:?}", e);
         }
-        Ok(llm_reply)
+
+        let mut heard: String = self
+            .delivered
+            .drain(..)
+            .filter(|(id, _)| *id <= played + 1)
+            .map(|(_, text)| text)
+            .collect();
+        heard.truncate(heard.trim_end().len());
+        heard.push_str("……");
+        heard
     }
 }
 
+/// A podcast left the queue of `PodcastStore`.
+fn podcast_taken(status: &Status) {
+    status.update(|s| s.queued_podcasts = s.queued_podcasts.saturating_sub(1));
+    METRICS.podcast_queue.dec();
+}
+
+enum Wake {
+    Comments(LinkedList<PlatformEvent>),
+    Host(HostUtterance),
+    Idle,
+}
+
 async fn stream_handler(
-    mut rx: tokio::sync::mpsc::UnboundedReceiver<Podcast>,
-    downstream: Arc<Downstream>,
-    stream_tx: tokio::sync::mpsc::UnboundedSender<CommentTx>,
+    rx: &mut tokio::sync::mpsc::UnboundedReceiver<Podcast>,
+    stream_tx: tokio::sync::mpsc::UnboundedSender<CommentRequest>,
+    host_rx: &mut HostRx,
+    state: Option<Arc<StateStore>>,
     llm_config: LLMConfig,
-    tts_config: TTSConfig,
+    mut llm_agent: LlmAgent,
+    mut shutdown: Shutdown,
 ) -> anyhow::Result<()> {
     let LLMConfig {
         llm_chat_url,
@@ -346,13 +864,61 @@ async fn stream_handler(
         sys_prompts,
         mut dynamic_prompts,
         history,
+        options,
+        idle,
+        context,
+        summary,
+        memory,
     } = llm_config;
+    let memory = match memory {
+        Some(memory) => Some(Arc::new(std::sync::Mutex::new(
+            crate::memory::ViewerMemory::load(memory)?,
+        ))),
+        None => None,
+    };
+    if let Some(memory) = &memory {
+        tokio::spawn(crate::memory::ViewerMemory::run_timer(Arc::downgrade(
+            memory,
+        )));
+    }
+    let mut summarizer = summary.map(crate::history::Summarizer::new);
+    if let Some(restored) = state.as_ref().and_then(|s| s.resume()) {
+        log::info!("restore {} turns", restored.dynamic_prompts.len());
+        dynamic_prompts = restored.dynamic_prompts;
+        if let Some(summarizer) = summarizer.as_mut() {
+            summarizer.restore(restored.summary);
+        }
+    }
 
-    let mut llm_agent = LlmAgent {
-        downstream: downstream.clone(),
-        tts_config,
+    let budget = match context {
+        Some(context) => {
+            let counter = crate::history::TokenCounter::new(&context.tokenizer)?;
+            let reserved = context
+                .reserved_output_tokens
+                .or(options.max_tokens.map(|t| t as usize))
+                .unwrap_or(512);
+            let max_tokens = context
+                .max_tokens
+                .saturating_sub(reserved)
+                .saturating_sub(counter.messages(sys_prompts.iter()));
+            log::info!("history token budget: {}", max_tokens);
+            Some(crate::history::TokenBudget {
+                counter,
+                max_tokens,
+            })
+        }
+        None => None,
     };
 
+    let idle_after = std::time::Duration::from_secs(idle.as_ref().map_or(0, |i| i.after_secs));
+    // consecutive idle segments, and the next topic to talk about
+    let mut idle_round = 0;
+    let mut idle_topic = 0;
+    let mut preempted = None;
+
+    let downstream = llm_agent.downstream.clone();
+    let mut chat = Comments::new(stream_tx);
+
     let token = if let Some(t) = api_key.as_ref() {
         format!("Bearer {}", t)
     } else {
@@ -381,48 +947,139 @@ async fn stream_handler(
             log::info!("podcast done");
         }
 
-        let timeout = std::time::Instant::now() + std::time::Duration::from_secs(60 * 3);
+        let timeout = tokio::time::Instant::now() + std::time::Duration::from_secs(60 * 3);
 
         loop {
             log::info!("wait comments");
-            let comments = tokio::time::timeout_at(timeout.into(), get_comments(&stream_tx)).await;
-            let comments = if comments.is_err() {
+            let wake = if let Some(wake) = preempted.take() {
+                wake
+            } else {
                 tokio::select! {
-                    p = rx.recv() => {
+                    batch = chat.get(SteamEvent::need_reply) => Wake::Comments(batch?),
+                    Some(host) = host_rx.recv() => Wake::Host(host),
+                    p = async {
+                        tokio::time::sleep_until(timeout).await;
+                        rx.recv().await
+                    } => {
                         if let Some(p) = p {
+                            podcast_taken(&llm_agent.status);
                             podcast = Some(p);
                             continue 'podcast;
+                        } else if shutdown.is_started() {
+                            break 'podcast;
                         } else {
                             return Err(anyhow::anyhow!("podcast rx closed"));
                         }
                     }
-                    comments= get_comments(&stream_tx) => {
-                        comments?
-                    }
+                    _ = tokio::time::sleep(idle_after), if idle.is_some() => Wake::Idle,
+                    _ = shutdown.started() => break 'podcast,
                 }
-            } else {
-                comments.unwrap()?
             };
-            log::info!("wait {} comments", comments.len());
+
+            let prompt = match wake {
+                Wake::Comments(comments) => {
+                    log::info!("wait {} comments", comments.len());
+                    idle_round = 0;
+                    let notes = match &memory {
+                        Some(memory) => {
+                            let notes =
+                                memory.lock().unwrap().record(&comments, crate::util::now());
+                            crate::memory::learn(
+                                memory.clone(),
+                                &comments,
+                                &llm_chat_url,
+                                &token,
+                                &options,
+                            );
+                            notes
+                        }
+                        None => String::new(),
+                    };
+                    parse_comments(comments) + &notes
+                }
+                Wake::Host(HostUtterance { name, text }) => {
+                    idle_round = 0;
+                    format!("以下是主播 {name} 对你说的话：\n{text}\n")
+                }
+                Wake::Idle => {
+                    let idle = idle.as_ref().unwrap();
+                    log::info!("chat is quiet, idle round {}", idle_round);
+                    let prompt = idle.prompt(idle_topic);
+                    idle_topic += 1;
+                    idle_round += 1;
+                    prompt
+                }
+            };
+            let is_idle = idle_round > 0;
 
             dynamic_prompts.push_back(Content {
                 role: crate::llm::llm::Role::User,
-                message: parse_comments(comments),
+                message: prompt,
             });
-            if dynamic_prompts.len() > history * 2 {
-                dynamic_prompts.pop_front();
-                dynamic_prompts.pop_front();
+            let story = summarizer.as_ref().and_then(|s| s.message());
+            let evicted = crate::history::trim(
+                &mut dynamic_prompts,
+                history * 2,
+                budget.as_ref().map(|b| b.without(story.iter())).as_ref(),
+            );
+            if !evicted.is_empty() {
+                log::info!("{} turns evicted from history", evicted.len());
+            }
+            if let Some(summarizer) = summarizer.as_mut() {
+                summarizer.evict(evicted);
+                summarizer.poll(&llm_chat_url, &token, &options).await;
             }
 
             log::debug!("llm_agent reply\n{:#?}", dynamic_prompts);
-            match llm_agent
-                .reply(
+            // anything interrupts the monologue, only priority events a reply
+            let barge_in = if is_idle {
+                SteamEvent::need_reply
+            } else {
+                SteamEvent::is_priority
+            };
+            let mut stopping = false;
+            let reply = {
+                let mut reply = std::pin::pin!(llm_agent.reply(
                     &llm_chat_url,
                     &token,
-                    sys_prompts.iter().chain(dynamic_prompts.iter()),
-                )
-                .await
-            {
+                    &options,
+                    sys_prompts
+                        .iter()
+                        .chain(story.iter())
+                        .chain(dynamic_prompts.iter()),
+                ));
+                let r = tokio::select! {
+                    r = &mut reply => Some(r),
+                    batch = chat.get(barge_in) => {
+                        preempted = Some(Wake::Comments(batch?));
+                        None
+                    }
+                    Some(host) = host_rx.recv() => {
+                        preempted = Some(Wake::Host(host));
+                        None
+                    }
+                    _ = shutdown.started() => {
+                        stopping = true;
+                        None
+                    }
+                };
+                if stopping {
+                    let grace = Duration::from_secs(shutdown.config.reply_grace_secs);
+                    log::info!("shutting down, finish the reply within {:?}", grace);
+                    tokio::time::timeout(grace, &mut reply).await.ok()
+                } else {
+                    r
+                }
+            };
+            let reply = match reply {
+                Some(r) => r,
+                None => {
+                    log::info!("reply interrupted");
+                    Ok(llm_agent.interrupt().await)
+                }
+            };
+
+            match reply {
                 Ok(reply) => {
                     log::info!("llm_agent reply done");
                     dynamic_prompts.push_back(Content {
@@ -434,6 +1091,723 @@ async fn stream_handler(
                     log::error!("llm_agent reply failed: {:?}", e);
                 }
             }
+
+            if let Some(state) = &state {
+                let summary = summarizer
+                    .as_ref()
+                    .map(|s| s.summary().to_string())
+                    .unwrap_or_default();
+                state.update(|s| {
+                    s.dynamic_prompts = dynamic_prompts.clone();
+                    s.summary = summary;
+                });
+            }
+            if stopping {
+                break 'podcast;
+            }
+        }
+    }
+
+    // queued podcasts, the one being grouped is handed over once the routes are gone
+    match shutdown.config.podcasts {
+        PodcastPolicy::Send => {
+            while let Some(podcast) = rx.recv().await {
+                podcast_taken(&llm_agent.status);
+                log::info!("shutting down, send podcast {}", podcast.title);
+                if let Err(e) = downstream.send(podcast).await {
+                    log::error!("send podcast failed: {:?}", e);
+                }
+            }
+        }
+        PodcastPolicy::Drop => {
+            rx.close();
+            let mut dropped = 0;
+            while rx.recv().await.is_some() {
+                podcast_taken(&llm_agent.status);
+                dropped += 1;
+            }
+            log::info!("shutting down, {} podcasts dropped", dropped);
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reply_pipeline() {
+    use crate::tts::TtsEngine;
+    use futures_util::{future::BoxFuture, StreamExt};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // TTS slows down for early sentences so an unordered pipeline would reorder them.
+    struct SlowTts {
+        running: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    impl TtsEngine for SlowTts {
+        fn name(&self) -> &str {
+            "slow"
+        }
+
+        fn format(&self) -> &str {
+            "mp3"
+        }
+
+        fn synthesize<'a>(
+            &'a self,
+            text: &'a str,
+            _voice: &'a str,
+        ) -> BoxFuture<'a, anyhow::Result<Bytes>> {
+            Box::pin(async move {
+                let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+                self.peak.fetch_max(running, Ordering::SeqCst);
+                let i: u64 = text.trim_start_matches("line ").trim().parse()?;
+                tokio::time::sleep(std::time::Duration::from_millis(100 - i * 20)).await;
+                self.running.fetch_sub(1, Ordering::SeqCst);
+                Ok(Bytes::from(text.to_string()))
+            })
         }
     }
+
+    let llm = axum::routing::post(|| async {
+        let chunks = (0..5).map(|i| {
+            let delta = serde_json::json!({
+                "choices": [{"delta": {"role": "assistant", "content": format!("line {i}\n")}, "finish_reason": null}]
+            });
+            format!("data: {delta}\n\n")
+        });
+        let chunks = futures_util::stream::iter(chunks).then(|c| async move {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            Ok::<_, std::io::Error>(c)
+        });
+        axum::body::Body::from_stream(chunks)
+    });
+
+    let received = Arc::new(std::sync::Mutex::new(vec![]));
+    let received_ = received.clone();
+    let segment = post(move |multipart: Multipart| async move {
+        let req = parse_from_multipart(multipart).await.unwrap();
+        assert_eq!(req.voice.as_deref(), req.text.as_deref().map(str::as_bytes));
+        assert_eq!(req.voice_format.as_deref(), Some("mp3"));
+        received_.lock().unwrap().push(req.text.unwrap());
+        "ok"
+    });
+
+    let app = Router::new()
+        .route("/v1/chat/completions", llm)
+        .route("/segment", segment);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let engine = Arc::new(SlowTts {
+        running: AtomicUsize::new(0),
+        peak: AtomicUsize::new(0),
+    });
+    let mut agent = LlmAgent::new(
+        Arc::new(Downstream {
+            update_title_url: String::new(),
+            segment_url: format!("http://{addr}/segment"),
+            stop_url: None,
+            playback: Playback::new(Duration::ZERO),
+        }),
+        Tts {
+            engine: engine.clone(),
+            voice: "voice".to_string(),
+            vtb_name: "vtb".to_string(),
+        },
+        3,
+        Default::default(),
+    );
+
+    let prompts: Vec<Content> = vec![];
+    let reply = agent
+        .reply(
+            &format!("http://{addr}/v1/chat/completions"),
+            "",
+            &ChatOptions::default(),
+            prompts,
+        )
+        .await
+        .unwrap();
+
+    let expect: Vec<String> = (0..5).map(|i| format!("line {i}\n")).collect();
+    assert_eq!(reply, expect.concat());
+    assert_eq!(*received.lock().unwrap(), expect);
+    assert!(engine.peak.load(Ordering::SeqCst) > 1);
+
+    let report = agent.status.report();
+    assert!(report.reply.is_none() && report.last_error.is_none());
+    assert!(report.llm_latency_ms.is_some() && report.tts_latency_ms.is_some());
+}
+
+#[tokio::test]
+async fn test_comments_held() {
+    use crate::stream_platform::User;
+    use futures_util::FutureExt;
+
+    let (stream_tx, mut stream_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut chat = Comments::new(stream_tx);
+    let comment = PlatformEvent {
+        platform: "twitch".to_string(),
+        source: "twitch".to_string(),
+        event: SteamEvent::Comment {
+            user: User::new(1, "bob"),
+            content: "hi".to_string(),
+        },
+    };
+    let super_chat = PlatformEvent {
+        platform: "twitch".to_string(),
+        source: "twitch".to_string(),
+        event: SteamEvent::SuperChat {
+            user: User::new(2, "alice"),
+            content: "thanks".to_string(),
+            amount: 5.0,
+            currency: "USD".to_string(),
+        },
+    };
+
+    // dropped before llm_loop answers, llm_loop keeps the events
+    assert!(chat.get(SteamEvent::need_reply).now_or_never().is_none());
+    let req: CommentRequest = stream_rx.recv().await.unwrap();
+    assert!(req.tx.send(LinkedList::from([comment.clone()])).is_err());
+
+    // dropped after llm_loop answered, e.g. another select! branch won
+    {
+        let get = chat.get(SteamEvent::need_reply);
+        tokio::pin!(get);
+        assert!((&mut get).now_or_never().is_none());
+        let req: CommentRequest = stream_rx.recv().await.unwrap();
+        req.tx.send(LinkedList::from([comment.clone()])).unwrap();
+    }
+    assert_eq!(
+        chat.get(SteamEvent::need_reply).await.unwrap(),
+        LinkedList::from([comment.clone()])
+    );
+
+    // held events that don't pass `wake` go with the next batch
+    {
+        let get = chat.get(SteamEvent::need_reply);
+        tokio::pin!(get);
+        assert!((&mut get).now_or_never().is_none());
+        let req: CommentRequest = stream_rx.recv().await.unwrap();
+        req.tx.send(LinkedList::from([comment.clone()])).unwrap();
+    }
+    let get = tokio::spawn(async move { chat.get(SteamEvent::is_priority).await });
+    let req: CommentRequest = stream_rx.recv().await.unwrap();
+    req.tx.send(LinkedList::from([super_chat.clone()])).unwrap();
+    assert_eq!(
+        get.await.unwrap().unwrap(),
+        LinkedList::from([comment, super_chat])
+    );
+}
+
+#[tokio::test]
+async fn test_host_voice() {
+    let whisper = post(|mut multipart: Multipart| async move {
+        let mut file = vec![];
+        while let Some(field) = multipart.next_field().await.unwrap() {
+            if field.name() == Some("file") {
+                file = field.bytes().await.unwrap().to_vec();
+            }
+        }
+        assert_eq!(file, b"RIFF");
+        axum::Json(serde_json::json!({"text": " What do you think about cats? "}))
+    });
+    let app = Router::new().route("/v1/audio/transcriptions", whisper);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let (host_tx, mut host_rx) = tokio::sync::mpsc::unbounded_channel::<HostUtterance>();
+    let app = Router::new()
+        .route("/host_voice", post(host_voice))
+        .layer(Extension(Arc::new(AsrConfig {
+            url: format!("http://{addr}/v1/audio/transcriptions"),
+            lang: "en".to_string(),
+            host_name: "host".to_string(),
+        })))
+        .layer(Extension(host_tx));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "voice",
+            Part::bytes(b"RIFF".to_vec()).file_name("voice.wav"),
+        )
+        .text("name", "cohost");
+    let text = reqwest::Client::new()
+        .post(format!("http://{addr}/host_voice"))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(text, "What do you think about cats?");
+    assert_eq!(
+        host_rx.recv().await.unwrap(),
+        HostUtterance {
+            name: "cohost".to_string(),
+            text: "What do you think about cats?".to_string(),
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_playback_pacing() {
+    let played = Arc::new(std::sync::Mutex::new(vec![]));
+    let played_ = played.clone();
+    let downstream = Arc::new(std::sync::OnceLock::<Arc<Downstream>>::new());
+    let downstream_ = downstream.clone();
+    let downstream__ = downstream.clone();
+
+    // plays one segment at a time, 30ms each
+    let (play_tx, mut play_rx) = tokio::sync::mpsc::unbounded_channel::<u64>();
+    let segment = post(move |mut multipart: Multipart| async move {
+        while let Some(field) = multipart.next_field().await.unwrap() {
+            if field.name() == Some("id") {
+                let id: u64 = field.text().await.unwrap().parse().unwrap();
+                // the playing segment and this one at most
+                let done = *downstream__.get().unwrap().playback.done.borrow();
+                assert!(done + 2 >= id, "segment {id} sent while {done} done");
+                play_tx.send(id).unwrap();
+            }
+        }
+        "ok"
+    });
+    tokio::spawn(async move {
+        while let Some(id) = play_rx.recv().await {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            played_.lock().unwrap().push(id);
+            downstream_.get().unwrap().playback.finish(Some(id));
+        }
+    });
+
+    let app = Router::new()
+        .route("/update_title", post(|| async { "ok" }))
+        .route("/segment", segment);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let _ = downstream.set(Arc::new(Downstream {
+        update_title_url: format!("http://{addr}/update_title"),
+        segment_url: format!("http://{addr}/segment"),
+        stop_url: None,
+        playback: Playback::new(Duration::from_secs(5)),
+    }));
+    let downstream = downstream.get().unwrap();
+
+    let segment = |i: usize| SendMsgRequest {
+        vtb_name: "vtb".to_string(),
+        text: Some(format!("line {i}")),
+        motion: None,
+        voice: None,
+        voice_format: None,
+    };
+    let podcast = Podcast {
+        title: "title".to_string(),
+        segment: (0..5).map(segment).collect(),
+    };
+    downstream.send(podcast).await.unwrap();
+
+    // send returns after the last segment was played
+    assert_eq!(*played.lock().unwrap(), vec![1, 2, 3, 4, 5]);
+
+    // a lost callback only stalls for the timeout
+    let playback = Playback::new(Duration::from_millis(10));
+    let id = playback.next_id();
+    playback.wait(id).await;
+    assert_eq!(*playback.done.borrow(), id);
+    playback.finish(None);
+    assert_eq!(*playback.done.borrow(), id);
+}
+
+#[tokio::test]
+async fn test_barge_in() {
+    use crate::{
+        stream_platform::{multi::MultiPlatform, StreamPlatform, User},
+        tts::TtsEngine,
+    };
+    use futures_util::{future::BoxFuture, StreamExt};
+
+    struct EchoTts;
+
+    impl TtsEngine for EchoTts {
+        fn name(&self) -> &str {
+            "echo"
+        }
+
+        fn synthesize<'a>(
+            &'a self,
+            text: &'a str,
+            _voice: &'a str,
+        ) -> BoxFuture<'a, anyhow::Result<Bytes>> {
+            Box::pin(async move { Ok(Bytes::from(text.to_string())) })
+        }
+    }
+
+    struct Fake(tokio::sync::mpsc::UnboundedReceiver<SteamEvent>);
+
+    impl StreamPlatform for Fake {
+        async fn next_event(&mut self) -> anyhow::Result<SteamEvent> {
+            self.0.recv().await.ok_or(anyhow::anyhow!("fake closed"))
+        }
+    }
+
+    // every reply is 20 lines, slower than they are played
+    let requests = Arc::new(std::sync::Mutex::new(vec![]));
+    let requests_ = requests.clone();
+    let llm = post(
+        move |axum::Json(body): axum::Json<serde_json::Value>| async move {
+            requests_.lock().unwrap().push(body["messages"].clone());
+            let chunks = (0..20).map(|i| {
+                let delta = serde_json::json!({
+                    "choices": [{"delta": {"content": format!("line {i}\n")}}]
+                });
+                format!("data: {delta}\n\n")
+            });
+            let chunks = futures_util::stream::iter(chunks).then(|c| async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                Ok::<_, std::io::Error>(c)
+            });
+            axum::body::Body::from_stream(chunks)
+        },
+    );
+
+    let (play_tx, mut play_rx) = tokio::sync::mpsc::unbounded_channel::<u64>();
+    let segment = post(move |mut multipart: Multipart| async move {
+        while let Some(field) = multipart.next_field().await.unwrap() {
+            if field.name() == Some("id") {
+                play_tx
+                    .send(field.text().await.unwrap().parse().unwrap())
+                    .unwrap();
+            }
+        }
+        "ok"
+    });
+    let stopped = Arc::new(AtomicU64::new(0));
+    let stopped_ = stopped.clone();
+    let stop = post(move || async move {
+        stopped_.fetch_add(1, Ordering::SeqCst);
+        "ok"
+    });
+
+    let app = Router::new()
+        .route("/v1/chat/completions", llm)
+        .route("/segment", segment)
+        .route("/stop", stop);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let downstream = Arc::new(Downstream {
+        update_title_url: String::new(),
+        segment_url: format!("http://{addr}/segment"),
+        stop_url: Some(format!("http://{addr}/stop")),
+        playback: Playback::new(Duration::from_secs(5)),
+    });
+    let downstream_ = downstream.clone();
+    tokio::spawn(async move {
+        while let Some(id) = play_rx.recv().await {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            downstream_.playback.finish(Some(id));
+        }
+    });
+    let llm_agent = LlmAgent::new(
+        downstream.clone(),
+        Tts {
+            engine: Arc::new(EchoTts),
+            voice: "voice".to_string(),
+            vtb_name: "vtb".to_string(),
+        },
+        3,
+        Default::default(),
+    );
+    let llm_config: LLMConfig = toml::from_str(&format!(
+        "llm_chat_url = \"http://{addr}/v1/chat/completions\"\nhistory = 5"
+    ))
+    .unwrap();
+
+    let (event_tx, event_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut platforms = MultiPlatform::new();
+    platforms.push("twitch", Fake(event_rx));
+    let (stream_tx, mut stream_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        crate::stream_platform::llm_loop(10, &mut stream_rx, platforms, None, Default::default())
+            .await
+    });
+
+    let (_podcast_tx, mut podcast_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (_host_tx, mut host_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (shutdown_tx, signal) = tokio::sync::watch::channel(false);
+    let shutdown = Shutdown {
+        signal,
+        config: ShutdownConfig {
+            reply_grace_secs: 0,
+            podcasts: PodcastPolicy::Drop,
+            ..Default::default()
+        },
+    };
+    let handler = tokio::spawn(async move {
+        stream_handler(
+            &mut podcast_rx,
+            stream_tx,
+            &mut host_rx,
+            None,
+            llm_config,
+            llm_agent,
+            shutdown,
+        )
+        .await
+    });
+
+    event_tx
+        .send(SteamEvent::Comment {
+            user: User::new(1, "bob"),
+            content: "hi".to_string(),
+        })
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    // a plain comment waits for the reply to end
+    event_tx
+        .send(SteamEvent::Comment {
+            user: User::new(3, "carol"),
+            content: "hello".to_string(),
+        })
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(requests.lock().unwrap().len(), 1);
+    let played = downstream.playback.played();
+    event_tx
+        .send(SteamEvent::SuperChat {
+            user: User::new(2, "alice"),
+            content: "sing a song".to_string(),
+            amount: 5.0,
+            currency: "USD".to_string(),
+        })
+        .unwrap();
+
+    let wait = async {
+        while requests.lock().unwrap().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), wait)
+        .await
+        .unwrap();
+    assert_eq!(stopped.load(Ordering::SeqCst), 1);
+
+    // the cut off reply is kept as far as it was heard, before the events it gave way to
+    let messages = requests.lock().unwrap()[1].clone();
+    let messages = messages.as_array().unwrap();
+    assert_eq!(messages.len(), 3);
+    assert!(messages[0]["content"].as_str().unwrap().contains("bob: hi"));
+    assert_eq!(messages[1]["role"], "assistant");
+    let heard = messages[1]["content"].as_str().unwrap();
+    let full: String = (0..20).map(|i| format!("line {i}\n")).collect();
+    let heard = heard.strip_suffix("……").unwrap();
+    assert!(played > 0 && played < 19, "{played}");
+    assert!(heard.len() < full.trim_end().len() && full.starts_with(heard));
+    let events = messages[2]["content"].as_str().unwrap();
+    assert!(events.contains("carol: hello") && events.contains("alice: sing a song"));
+
+    shutdown_tx.send(true).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), handler)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_shutdown_podcasts() {
+    let received = Arc::new(std::sync::Mutex::new(vec![]));
+    let received_ = received.clone();
+    let update_title = post(
+        move |axum::Json(body): axum::Json<serde_json::Value>| async move {
+            received_
+                .lock()
+                .unwrap()
+                .push(body["title"].as_str().unwrap().to_string());
+            "ok"
+        },
+    );
+    let received_ = received.clone();
+    let segment = post(move |multipart: Multipart| async move {
+        let req = parse_from_multipart(multipart).await.unwrap();
+        received_.lock().unwrap().push(req.text.unwrap());
+        "ok"
+    });
+    let app = Router::new()
+        .route("/update_title", update_title)
+        .route("/segment", segment);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let run = |podcasts: PodcastPolicy| async move {
+        let llm_agent = LlmAgent::new(
+            Arc::new(Downstream {
+                update_title_url: format!("http://{addr}/update_title"),
+                segment_url: format!("http://{addr}/segment"),
+                stop_url: None,
+                playback: Playback::new(Duration::ZERO),
+            }),
+            Tts {
+                engine: Arc::new(crate::tts::stable::StableTts {
+                    base_url: String::new(),
+                }),
+                voice: "voice".to_string(),
+                vtb_name: "vtb".to_string(),
+            },
+            1,
+            Default::default(),
+        );
+        let llm_config: LLMConfig =
+            toml::from_str("llm_chat_url = \"http://127.0.0.1:1\"\nhistory = 1").unwrap();
+
+        let (podcast_tx, mut podcast_rx) = tokio::sync::mpsc::unbounded_channel();
+        for title in ["a", "b"] {
+            podcast_tx
+                .send(Podcast {
+                    title: title.to_string(),
+                    segment: vec![SendMsgRequest {
+                        vtb_name: "vtb".to_string(),
+                        text: Some(format!("{title} 1")),
+                        motion: None,
+                        voice: None,
+                        voice_format: None,
+                    }],
+                })
+                .unwrap();
+        }
+        drop(podcast_tx);
+        let (stream_tx, _stream_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_host_tx, mut host_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_signal_tx, signal) = tokio::sync::watch::channel(true);
+        let shutdown = Shutdown {
+            signal,
+            config: ShutdownConfig {
+                podcasts,
+                ..Default::default()
+            },
+        };
+
+        stream_handler(
+            &mut podcast_rx,
+            stream_tx,
+            &mut host_rx,
+            None,
+            llm_config,
+            llm_agent,
+            shutdown,
+        )
+        .await
+        .unwrap();
+    };
+
+    run(PodcastPolicy::Drop).await;
+    assert!(received.lock().unwrap().is_empty());
+
+    run(PodcastPolicy::Send).await;
+    assert_eq!(*received.lock().unwrap(), vec!["a", "a 1", "b", "b 1"]);
+}
+
+#[tokio::test]
+async fn test_end_to_end() {
+    use crate::stream_platform::{console::ConsoleChat, multi::MultiPlatform};
+
+    let mock = crate::mock::MockServer::start().await;
+    mock.reply("你好，bob。欢迎来到直播间！");
+    mock.reply("现在是晚上八点。");
+
+    let status = Arc::new(Status::default());
+    let (supervisor, _fatal_rx) = Supervisor::new(Default::default());
+
+    // the second comment comes in while the first is answered
+    let chat = "bob: 大家好\nalice: 现在几点了？\n";
+    let mut platforms = MultiPlatform::with_status(status.clone());
+    platforms.push(
+        "console",
+        ConsoleChat::new(chat.as_bytes(), Duration::from_millis(300)),
+    );
+    let (stream_tx, mut stream_rx) = tokio::sync::mpsc::unbounded_channel();
+    let status_ = status.clone();
+    tokio::spawn(async move {
+        crate::stream_platform::llm_loop(20, &mut stream_rx, platforms, None, status_).await
+    });
+
+    let llm_agent = LlmAgent::new(
+        Arc::new(Downstream::new(mock.downstream())),
+        Tts {
+            engine: Arc::new(crate::tts::stable::StableTts {
+                base_url: format!("{}/tts", mock.url),
+            }),
+            voice: "kelly".to_string(),
+            vtb_name: "vtb".to_string(),
+        },
+        2,
+        status.clone(),
+    );
+    let llm_config: LLMConfig = toml::from_str(&format!(
+        "llm_chat_url = \"{}/v1/chat/completions\"\nhistory = 5",
+        mock.url
+    ))
+    .unwrap();
+    let (shutdown_tx, signal) = tokio::sync::watch::channel(false);
+    let shutdown = Shutdown {
+        signal,
+        config: Default::default(),
+    };
+    let (app, handler) = router(
+        llm_config,
+        llm_agent,
+        None,
+        None,
+        stream_tx,
+        shutdown,
+        &supervisor,
+    );
+
+    let segments = mock.wait_segments(2).await;
+    let text: String = segments.iter().filter_map(|s| s.text.clone()).collect();
+    assert_eq!(text, "你好，bob。欢迎来到直播间！现在是晚上八点。");
+    assert!(segments.windows(2).all(|w| w[0].id < w[1].id));
+    for segment in &segments {
+        assert_eq!(segment.vtb_name, "vtb");
+        let voice = segment.voice.as_ref().unwrap();
+        assert!(crate::tts::wav_duration(voice).is_some());
+    }
+    assert!(mock
+        .state
+        .speech
+        .lock()
+        .unwrap()
+        .iter()
+        .all(|(speaker, _)| speaker == "kelly"));
+
+    // the second prompt carries the first turn
+    let requests = mock.state.requests.lock().unwrap().clone();
+    assert_eq!(requests.len(), 2);
+    let messages = requests[1]["messages"].as_array().unwrap();
+    let turns: Vec<&str> = messages
+        .iter()
+        .map(|m| m["content"].as_str().unwrap())
+        .collect();
+    assert!(turns[0].contains("bob") && turns[0].contains("大家好"));
+    assert_eq!(turns[1], "你好，bob。欢迎来到直播间！");
+    assert!(turns[2].contains("alice") && turns[2].contains("现在几点了？"));
+
+    // queued podcasts are handed over once the routes are gone
+    shutdown_tx.send(true).unwrap();
+    drop(app);
+    tokio::time::timeout(Duration::from_secs(5), handler)
+        .await
+        .unwrap()
+        .unwrap();
 }
@@ -1,6 +1,6 @@
 use std::collections::LinkedList;
 
-use crate::llm::llm::Content;
+use crate::llm::{llm::Content, ChatOptions};
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct LLMConfig {
@@ -12,15 +12,146 @@ pub struct LLMConfig {
     #[serde(default)]
     pub dynamic_prompts: LinkedList<Content>,
     pub history: usize,
+    /// `model`, `temperature`, `max_tokens`... directly under `[llm]`,
+    /// anything else under `[llm.extra]`.
+    #[serde(flatten)]
+    pub options: ChatOptions,
+    /// Talk on its own when the chat is quiet, disabled if absent.
+    #[serde(default)]
+    pub idle: Option<IdleConfig>,
+    /// Token budget of the prompt, only `history` applies if absent.
+    #[serde(default)]
+    pub context: Option<ContextConfig>,
+    /// Fold turns evicted from the history into a summary, disabled if absent.
+    #[serde(default)]
+    pub summary: Option<SummaryConfig>,
+    /// Remember viewers across streams, disabled if absent.
+    #[serde(default)]
+    pub memory: Option<MemoryConfig>,
+}
+
+fn default_memory_path() -> String {
+    "viewers.json".to_string()
+}
+
+fn default_max_facts() -> usize {
+    20
+}
+
+fn default_memory_interval_secs() -> u64 {
+    10
+}
+
+fn default_facts_prompt() -> String {
+    "你会看到直播间观众的评论，每条前面是观众的编号。请找出关于观众本人、值得长期记住的事实，比如称呼、爱好、所在地和经历。每行一条，格式为「编号: 事实」，没有就什么都不输出。".to_string()
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct MemoryConfig {
+    /// JSON file the viewers are kept in.
+    #[serde(default = "default_memory_path")]
+    pub path: String,
+    /// Facts kept per viewer, the oldest are forgotten first. 0 disables learning facts.
+    #[serde(default = "default_max_facts")]
+    pub max_facts: usize,
+    /// System prompt asking the LLM for facts about the viewers in a batch of comments.
+    #[serde(default = "default_facts_prompt")]
+    pub facts_prompt: String,
+    /// How often changed viewers are written to `path`.
+    #[serde(default = "default_memory_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_summary_prompt() -> String {
+    "你是直播记录员。请把已有的直播经过和新的对话合并成一段简洁的直播经过，保留观众的名字、发生的事件和做出的约定，不超过300字，只输出直播经过。".to_string()
+}
+
+fn default_story_prompt() -> String {
+    "以下是直播到目前为止的经过：\n{summary}".to_string()
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SummaryConfig {
+    /// System prompt of the summarizing request.
+    #[serde(default = "default_summary_prompt")]
+    pub prompt: String,
+    /// System message kept in the prompt, `{summary}` is replaced by the summary.
+    #[serde(default = "default_story_prompt")]
+    pub story_prompt: String,
+    /// Length limit of the summary, in place of the `max_tokens` of the chat
+    /// replies. Unlimited by default.
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+}
+
+fn default_tokenizer() -> String {
+    "cl100k_base".to_string()
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ContextConfig {
+    /// Context window of the model.
+    pub max_tokens: usize,
+    /// Kept free for the reply, `max_tokens` of the chat options or 512 if unset.
+    #[serde(default)]
+    pub reserved_output_tokens: Option<usize>,
+    /// `cl100k_base`, `o200k_base`, `p50k_base` or `r50k_base`.
+    #[serde(default = "default_tokenizer")]
+    pub tokenizer: String,
+}
+
+fn default_idle_after_secs() -> u64 {
+    30
+}
+
+fn default_idle_topic_prompt() -> String {
+    "直播间暂时没有新的评论，请你主动和观众聊聊「{topic}」。".to_string()
+}
+
+fn default_idle_continue_prompt() -> String {
+    "直播间暂时没有新的评论，请你接着刚才的内容继续和观众聊天。".to_string()
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct IdleConfig {
+    /// Seconds of silence before the agent starts talking on its own.
+    #[serde(default = "default_idle_after_secs")]
+    pub after_secs: u64,
+    /// Used in turn. Without topics the agent continues from the history.
+    #[serde(default)]
+    pub topics: Vec<String>,
+    /// `{topic}` is replaced by the current topic.
+    #[serde(default = "default_idle_topic_prompt")]
+    pub topic_prompt: String,
+    #[serde(default = "default_idle_continue_prompt")]
+    pub continue_prompt: String,
+}
+
+impl IdleConfig {
+    /// The self-directed user prompt of the `round`-th idle segment.
+    pub fn prompt(&self, round: usize) -> String {
+        if self.topics.is_empty() {
+            self.continue_prompt.clone()
+        } else {
+            let topic = &self.topics[round % self.topics.len()];
+            self.topic_prompt.replace("{topic}", topic)
+        }
+    }
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct FishTTS {
+    #[serde(default = "default_fish_tts_url")]
+    pub base_url: String,
     pub api_key: String,
     pub speaker: String,
     pub vtb_name: String,
 }
 
+fn default_fish_tts_url() -> String {
+    "https://api.fish.audio/v1/tts".to_string()
+}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct StableTTS {
     pub base_url: String,
@@ -28,11 +159,59 @@ pub struct StableTTS {
     pub vtb_name: String,
 }
 
+fn default_openai_tts_format() -> String {
+    "wav".to_string()
+}
+
+/// Any server implementing OpenAI's `/v1/audio/speech`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct OpenAITTS {
+    /// Full endpoint, e.g. `https://api.openai.com/v1/audio/speech`.
+    pub base_url: String,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    pub model: String,
+    pub voice: String,
+    #[serde(default = "default_openai_tts_format")]
+    pub response_format: String,
+    #[serde(default)]
+    pub speed: Option<f32>,
+    pub vtb_name: String,
+}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(tag = "platform")]
 pub enum TTSConfig {
     Stable(StableTTS),
     Fish(FishTTS),
+    OpenAI(OpenAITTS),
+}
+
+impl TTSConfig {
+    /// The `platform` tag, used as key of `TtsRegistry`.
+    pub fn platform(&self) -> &'static str {
+        match self {
+            TTSConfig::Stable(_) => "Stable",
+            TTSConfig::Fish(_) => "Fish",
+            TTSConfig::OpenAI(_) => "OpenAI",
+        }
+    }
+
+    pub fn speaker(&self) -> &str {
+        match self {
+            TTSConfig::Stable(c) => &c.speaker,
+            TTSConfig::Fish(c) => &c.speaker,
+            TTSConfig::OpenAI(c) => &c.voice,
+        }
+    }
+
+    pub fn vtb_name(&self) -> &str {
+        match self {
+            TTSConfig::Stable(c) => &c.vtb_name,
+            TTSConfig::Fish(c) => &c.vtb_name,
+            TTSConfig::OpenAI(c) => &c.vtb_name,
+        }
+    }
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -40,25 +219,171 @@ pub enum TTSConfig {
 pub enum StreamPlatFormConfig {
     Bilibili(BilibiliConfig),
     Restream(RestreamConfig),
+    Twitch(TwitchConfig),
+    /// `user: message` lines typed on stdin.
+    Console(ConsoleConfig),
+    /// `user: message` lines of a text file.
+    File(ChatFileConfig),
+}
+
+impl StreamPlatFormConfig {
+    /// The platform type, e.g. `twitch`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            StreamPlatFormConfig::Bilibili(_) => "bilibili",
+            StreamPlatFormConfig::Restream(_) => "restream",
+            StreamPlatFormConfig::Twitch(_) => "twitch",
+            StreamPlatFormConfig::Console(_) => "console",
+            StreamPlatFormConfig::File(_) => "file",
+        }
+    }
+
+    /// Tag of the events coming from this platform, its `name` or else its type.
+    pub fn name(&self) -> &str {
+        let name = match self {
+            StreamPlatFormConfig::Bilibili(c) => &c.name,
+            StreamPlatFormConfig::Restream(c) => &c.name,
+            StreamPlatFormConfig::Twitch(c) => &c.name,
+            StreamPlatFormConfig::Console(c) => &c.name,
+            StreamPlatFormConfig::File(c) => &c.name,
+        };
+        name.as_deref().unwrap_or(self.kind())
+    }
+
+    /// Two platforms of the same name would mix up their events and status.
+    pub fn check_names(platforms: &[Self]) -> anyhow::Result<()> {
+        let mut names = std::collections::HashSet::new();
+        for platform in platforms {
+            if !names.insert(platform.name()) {
+                return Err(anyhow::anyhow!(
+                    "duplicate platform name {}, set `name` to tell them apart",
+                    platform.name()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn max_comment(&self) -> usize {
+        match self {
+            StreamPlatFormConfig::Bilibili(c) => c.max_comment,
+            StreamPlatFormConfig::Restream(c) => c.max_comment,
+            StreamPlatFormConfig::Twitch(c) => c.max_comment,
+            StreamPlatFormConfig::Console(c) => c.max_comment,
+            StreamPlatFormConfig::File(c) => c.max_comment,
+        }
+    }
+}
+
+/// Accept both a single `[platform]` table and a `[[platform]]` list.
+/// The shape is picked first, so a bad entry reports its own error.
+fn one_or_many_platform<'de, D>(deserializer: D) -> Result<Vec<StreamPlatFormConfig>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    let value: serde_json::Value = serde::Deserialize::deserialize(deserializer)?;
+    if value.is_array() {
+        serde_json::from_value(value).map_err(D::Error::custom)
+    } else {
+        serde_json::from_value(value)
+            .map(|c| vec![c])
+            .map_err(D::Error::custom)
+    }
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct BilibiliConfig {
+    #[serde(default)]
+    pub name: Option<String>,
     pub room_id: u64,
     pub max_retry: u32,
     pub token_path: String,
     pub max_comment: usize,
 }
 
+fn default_max_retry() -> u32 {
+    5
+}
+
+fn default_ping_interval_secs() -> u64 {
+    30
+}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct RestreamConfig {
+    #[serde(default)]
+    pub name: Option<String>,
     pub url: String,
     pub max_comment: usize,
+    /// Reconnect attempts before giving up, reset after every successful connect.
+    #[serde(default = "default_max_retry")]
+    pub max_retry: u32,
+    /// At least 1.
+    #[serde(default = "default_ping_interval_secs")]
+    pub ping_interval_secs: u64,
+}
+
+fn default_twitch_url() -> String {
+    "wss://irc-ws.chat.twitch.tv:443".to_string()
+}
+
+fn default_twitch_nick() -> String {
+    "justinfan12345".to_string()
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct TwitchConfig {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default = "default_twitch_url")]
+    pub url: String,
+    pub channel: String,
+    /// Login name, anonymous (read only) by default.
+    #[serde(default = "default_twitch_nick")]
+    pub nick: String,
+    /// OAuth token, with or without the `oauth:` prefix. Empty for anonymous.
+    #[serde(default)]
+    pub token: String,
+    pub max_comment: usize,
+    /// Reconnect attempts when the connection drops or twitch asks to reconnect.
+    #[serde(default = "default_max_retry")]
+    pub max_retry: u32,
+}
+
+fn default_console_max_comment() -> usize {
+    20
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ConsoleConfig {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default = "default_console_max_comment")]
+    pub max_comment: usize,
+}
+
+fn default_chat_file_interval_ms() -> u64 {
+    1000
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ChatFileConfig {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub path: String,
+    /// Wait between lines.
+    #[serde(default = "default_chat_file_interval_ms")]
+    pub interval_ms: u64,
+    #[serde(default = "default_console_max_comment")]
+    pub max_comment: usize,
 }
 
 #[test]
 fn test_serde() {
     let bilibili = StreamPlatFormConfig::Bilibili(BilibiliConfig {
+        name: None,
         room_id: 123456,
         max_retry: 3,
         token_path: "token.txt".to_string(),
@@ -68,12 +393,59 @@ fn test_serde() {
     println!("{}", s);
 
     let restream = StreamPlatFormConfig::Restream(RestreamConfig {
+        name: None,
         url: "ws://httpbin.org".to_string(),
         max_comment: 20,
+        max_retry: 5,
+        ping_interval_secs: 30,
     });
     let s = serde_json::to_string(&restream).unwrap();
     println!("{}", s);
 
+    let twitch: StreamPlatFormConfig = toml::from_str(
+        r#"
+        platform = "Twitch"
+        channel = "dallas"
+        max_comment = 20
+        "#,
+    )
+    .unwrap();
+    let StreamPlatFormConfig::Twitch(twitch) = twitch else {
+        panic!("expect twitch config");
+    };
+    assert_eq!(twitch.url, "wss://irc-ws.chat.twitch.tv:443");
+    assert_eq!(twitch.nick, "justinfan12345");
+    assert_eq!(twitch.max_retry, 5);
+
+    let file: StreamPlatFormConfig = toml::from_str(
+        r#"
+        platform = "File"
+        path = "chat.txt"
+        "#,
+    )
+    .unwrap();
+    let StreamPlatFormConfig::File(file) = file else {
+        panic!("expect file config");
+    };
+    assert_eq!((file.interval_ms, file.max_comment), (1000, 20));
+    let console: StreamPlatFormConfig = toml::from_str(r#"platform = "Console""#).unwrap();
+    assert_eq!(console.name(), "console");
+
+    let twitch_en: StreamPlatFormConfig = toml::from_str(
+        r#"
+        platform = "Twitch"
+        name = "twitch_en"
+        channel = "dallas"
+        max_comment = 20
+        "#,
+    )
+    .unwrap();
+    assert_eq!(twitch_en.name(), "twitch_en");
+    assert_eq!(twitch_en.kind(), "twitch");
+    let twitch = StreamPlatFormConfig::Twitch(twitch);
+    assert!(StreamPlatFormConfig::check_names(&[twitch.clone(), twitch_en.clone()]).is_ok());
+    assert!(StreamPlatFormConfig::check_names(&[twitch.clone(), twitch]).is_err());
+
     let config = Config {
         listen: "0.0.0.0:8080".to_string(),
         llm: LLMConfig {
@@ -85,28 +457,279 @@ fn test_serde() {
             dynamic_prompts: LinkedList::new(),
             history: 10,
             api_key: None,
+            options: ChatOptions {
+                model: Some("llama".to_string()),
+                max_tokens: Some(128),
+                ..Default::default()
+            },
+            idle: None,
+            context: None,
+            summary: None,
+            memory: None,
         },
-        platform: bilibili,
+        platform: vec![bilibili, restream],
         tts: TTSConfig::Stable(StableTTS {
             base_url: "http://tts.com".to_string(),
             speaker: "speaker".to_string(),
             vtb_name: "vtb".to_string(),
         }),
+        tts_concurrency: 3,
         downstream: DownstreamConfig {
             update_title_url: "http://update.com".to_string(),
             segment_url: "http://segment.com".to_string(),
+            playback_timeout_secs: 30,
+            stop_url: None,
         },
+        asr: None,
+        state: None,
+        shutdown: Default::default(),
+        supervisor: Default::default(),
+        recorder: None,
+        replay: None,
     };
     let config_str = serde_json::to_string(&config).unwrap();
     println!("{}", config_str);
     let config: Config = serde_json::from_str(&config_str).unwrap();
     println!("{:?}", config);
+    assert_eq!(config.platform.len(), 2);
+
+    let single: Config = toml::from_str(
+        r#"
+        listen = "0.0.0.0:8080"
+        [llm]
+        llm_chat_url = "http://llm.com"
+        history = 10
+        [tts]
+        platform = "Stable"
+        base_url = "http://tts.com"
+        speaker = "speaker"
+        vtb_name = "vtb"
+        [downstream]
+        update_title_url = "http://update.com"
+        segment_url = "http://segment.com"
+        [platform]
+        platform = "Twitch"
+        channel = "dallas"
+        max_comment = 20
+        "#,
+    )
+    .unwrap();
+    assert_eq!(single.platform.len(), 1);
+
+    let bad = toml::from_str::<Config>(
+        r#"
+        listen = "0.0.0.0:8080"
+        [llm]
+        llm_chat_url = "http://llm.com"
+        history = 10
+        [tts]
+        platform = "Stable"
+        base_url = "http://tts.com"
+        speaker = "speaker"
+        vtb_name = "vtb"
+        [downstream]
+        update_title_url = "http://update.com"
+        segment_url = "http://segment.com"
+        [[platform]]
+        platform = "Twitch"
+        max_comment = 20
+        "#,
+    )
+    .unwrap_err();
+    assert!(bad.to_string().contains("missing field `channel`"), "{bad}");
+
+    let tts: TTSConfig = toml::from_str(
+        r#"
+        platform = "OpenAI"
+        base_url = "http://localhost:8000/v1/audio/speech"
+        model = "tts-1"
+        voice = "alloy"
+        vtb_name = "vtb"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(tts.speaker(), "alloy");
+    let TTSConfig::OpenAI(tts) = tts else {
+        panic!("expect openai tts config");
+    };
+    assert_eq!(tts.response_format, "wav");
+    assert_eq!(tts.speed, None);
+
+    let context: ContextConfig = toml::from_str("max_tokens = 8192").unwrap();
+    assert_eq!(context.tokenizer, "cl100k_base");
+    assert_eq!(context.reserved_output_tokens, None);
+
+    let idle: IdleConfig = toml::from_str(r#"topics = ["cats", "dogs"]"#).unwrap();
+    assert_eq!(idle.after_secs, 30);
+    assert!(idle.prompt(0).contains("「cats」"));
+    assert!(idle.prompt(3).contains("「dogs」"));
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct DownstreamConfig {
     pub update_title_url: String,
     pub segment_url: String,
+    /// How long to wait for the `/callback` of a segment before sending on.
+    /// 0 disables pacing for downstreams that never call back.
+    #[serde(default = "default_playback_timeout_secs")]
+    pub playback_timeout_secs: u64,
+    /// Posted to when a reply is interrupted, to drop whatever is playing or queued.
+    #[serde(default)]
+    pub stop_url: Option<String>,
+}
+
+fn default_playback_timeout_secs() -> u64 {
+    30
+}
+
+fn default_host_name() -> String {
+    "主播".to_string()
+}
+
+/// Whisper compatible transcription of the host's voice posted to `/host_voice`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AsrConfig {
+    /// e.g. `http://localhost:8080/v1/audio/transcriptions`
+    pub url: String,
+    #[serde(default)]
+    pub lang: String,
+    /// Used when the request has no `name`.
+    #[serde(default = "default_host_name")]
+    pub host_name: String,
+}
+
+fn default_state_path() -> String {
+    "state.json".to_string()
+}
+
+fn default_state_interval_secs() -> u64 {
+    30
+}
+
+/// Snapshots of the conversation, restored when the same `session` starts again.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct StateConfig {
+    #[serde(default = "default_state_path")]
+    pub path: String,
+    /// e.g. the date or the id of the broadcast
+    pub session: String,
+    #[serde(default = "default_state_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_recorder_path() -> String {
+    "session.jsonl".to_string()
+}
+
+/// Transcript of every comment, prompt, chunk, synthesis and delivery.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RecorderConfig {
+    #[serde(default = "default_recorder_path")]
+    pub path: String,
+}
+
+/// Where `replay` gets LLM replies or speech from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ReplaySource {
+    /// The responses in the recording, speech is silence of the recorded length.
+    #[default]
+    Recorded,
+    /// The configured service.
+    Live,
+}
+
+fn default_replay_speed() -> f64 {
+    1.0
+}
+
+/// `llm_streaming replay` feeds the chat of a recording through the pipeline again.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ReplayConfig {
+    /// The recording to replay, never the one `[recorder]` writes to.
+    pub path: String,
+    /// 2.0 replays twice as fast, 0 without waiting at all.
+    #[serde(default = "default_replay_speed")]
+    pub speed: f64,
+    #[serde(default)]
+    pub llm: ReplaySource,
+    #[serde(default)]
+    pub tts: ReplaySource,
+}
+
+/// What happens to podcasts not played yet at shutdown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum PodcastPolicy {
+    #[default]
+    Send,
+    Drop,
+}
+
+fn default_reply_grace_secs() -> u64 {
+    10
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    120
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ShutdownConfig {
+    /// Seconds the current reply may take to finish before it is cut off.
+    #[serde(default = "default_reply_grace_secs")]
+    pub reply_grace_secs: u64,
+    #[serde(default)]
+    pub podcasts: PodcastPolicy,
+    /// Upper bound of the whole shutdown.
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            reply_grace_secs: default_reply_grace_secs(),
+            podcasts: PodcastPolicy::default(),
+            timeout_secs: default_shutdown_timeout_secs(),
+        }
+    }
+}
+
+fn default_max_restarts() -> u32 {
+    5
+}
+
+fn default_stable_secs() -> u64 {
+    60
+}
+
+fn default_backoff_base_ms() -> u64 {
+    1000
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SupervisorConfig {
+    /// Restarts in a row before the process gives up.
+    #[serde(default = "default_max_restarts")]
+    pub max_restarts: u32,
+    /// A task running this long is considered recovered.
+    #[serde(default = "default_stable_secs")]
+    pub stable_secs: u64,
+    #[serde(default = "default_backoff_base_ms")]
+    pub backoff_base_ms: u64,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        Self {
+            max_restarts: default_max_restarts(),
+            stable_secs: default_stable_secs(),
+            backoff_base_ms: default_backoff_base_ms(),
+        }
+    }
+}
+
+fn default_tts_concurrency() -> usize {
+    3
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -114,6 +737,22 @@ pub struct Config {
     pub listen: String,
     pub llm: LLMConfig,
     pub tts: TTSConfig,
-    pub platform: StreamPlatFormConfig,
+    /// Sentences synthesized ahead of the one being delivered.
+    #[serde(default = "default_tts_concurrency")]
+    pub tts_concurrency: usize,
+    #[serde(deserialize_with = "one_or_many_platform")]
+    pub platform: Vec<StreamPlatFormConfig>,
     pub downstream: DownstreamConfig,
+    #[serde(default)]
+    pub asr: Option<AsrConfig>,
+    #[serde(default)]
+    pub state: Option<StateConfig>,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub supervisor: SupervisorConfig,
+    #[serde(default)]
+    pub recorder: Option<RecorderConfig>,
+    #[serde(default)]
+    pub replay: Option<ReplayConfig>,
 }
@@ -0,0 +1,330 @@
+//! Conversation history kept within the model's context window.
+
+use std::collections::LinkedList;
+
+use tiktoken_rs::CoreBPE;
+
+use crate::{
+    config::SummaryConfig,
+    llm::{
+        llm::{Content, Role},
+        llm_stable, ChatOptions,
+    },
+};
+
+/// Role markers and separators of the chat template, roughly.
+const MESSAGE_OVERHEAD: usize = 4;
+
+#[derive(Clone, Copy)]
+pub struct TokenCounter {
+    bpe: &'static CoreBPE,
+}
+
+impl TokenCounter {
+    pub fn new(tokenizer: &str) -> anyhow::Result<Self> {
+        let bpe = match tokenizer {
+            "cl100k_base" => tiktoken_rs::cl100k_base_singleton(),
+            "o200k_base" => tiktoken_rs::o200k_base_singleton(),
+            "p50k_base" => tiktoken_rs::p50k_base_singleton(),
+            "r50k_base" => tiktoken_rs::r50k_base_singleton(),
+            _ => return Err(anyhow::anyhow!("unknown tokenizer {}", tokenizer)),
+        };
+        Ok(Self { bpe })
+    }
+
+    pub fn message(&self, content: &Content) -> usize {
+        self.bpe.encode_ordinary(&content.message).len() + MESSAGE_OVERHEAD
+    }
+
+    pub fn messages<'a, I: IntoIterator<Item = &'a Content>>(&self, contents: I) -> usize {
+        contents.into_iter().map(|c| self.message(c)).sum()
+    }
+}
+
+/// Tokens the history may take up.
+pub struct TokenBudget {
+    pub counter: TokenCounter,
+    pub max_tokens: usize,
+}
+
+impl TokenBudget {
+    /// What is left after `contents`, e.g. the summary.
+    pub fn without<'a, I: IntoIterator<Item = &'a Content>>(&self, contents: I) -> TokenBudget {
+        TokenBudget {
+            counter: self.counter,
+            max_tokens: self
+                .max_tokens
+                .saturating_sub(self.counter.messages(contents)),
+        }
+    }
+}
+
+/// Evicts the oldest turns until at most `max_messages` are left and they fit
+/// the `budget`. A user message goes together with the reply that follows it,
+/// the newest message is always kept. Returns the evicted turns, oldest first.
+pub fn trim(
+    turns: &mut LinkedList<Content>,
+    max_messages: usize,
+    budget: Option<&TokenBudget>,
+) -> Vec<Content> {
+    let mut tokens = budget.map_or(0, |b| b.counter.messages(turns.iter()));
+    let over = |len: usize, tokens: usize| {
+        len > max_messages || budget.is_some_and(|b| tokens > b.max_tokens)
+    };
+
+    let mut evicted = vec![];
+    while turns.len() > 1 && over(turns.len(), tokens) {
+        let mut iter = turns.iter();
+        let pair = matches!(
+            (iter.next(), iter.next()),
+            (
+                Some(Content {
+                    role: Role::User,
+                    ..
+                }),
+                Some(Content {
+                    role: Role::Assistant,
+                    ..
+                })
+            )
+        );
+        let n = if pair && turns.len() > 2 { 2 } else { 1 };
+        for _ in 0..n {
+            let turn = turns.pop_front().unwrap();
+            if let Some(b) = budget {
+                tokens -= b.counter.message(&turn);
+            }
+            evicted.push(turn);
+        }
+    }
+    if budget.is_some_and(|b| tokens > b.max_tokens) {
+        log::warn!("the newest message alone takes {} tokens", tokens);
+    }
+    evicted
+}
+
+/// Folds evicted turns into a running summary of the stream, one LLM request
+/// at a time in the background. Turns evicted meanwhile wait for the next one.
+pub struct Summarizer {
+    config: SummaryConfig,
+    summary: String,
+    backlog: Vec<Content>,
+    folding: Option<(
+        Vec<Content>,
+        tokio::task::JoinHandle<anyhow::Result<String>>,
+    )>,
+}
+
+impl Summarizer {
+    pub fn new(config: SummaryConfig) -> Self {
+        Self {
+            config,
+            summary: String::new(),
+            backlog: vec![],
+            folding: None,
+        }
+    }
+
+    pub fn summary(&self) -> &str {
+        &self.summary
+    }
+
+    /// Continues from the summary of a previous run.
+    pub fn restore(&mut self, summary: String) {
+        self.summary = summary;
+    }
+
+    /// The "story so far" system message, once there is one.
+    pub fn message(&self) -> Option<Content> {
+        if self.summary.is_empty() {
+            return None;
+        }
+        Some(Content {
+            role: Role::System,
+            message: self.config.story_prompt.replace("{summary}", &self.summary),
+        })
+    }
+
+    pub fn evict(&mut self, turns: Vec<Content>) {
+        self.backlog.extend(turns);
+    }
+
+    /// Picks up a finished fold and starts the next one if turns are waiting.
+    /// `options` are those of the chat, but for `max_tokens`.
+    pub async fn poll(&mut self, llm_url: &str, token: &str, options: &ChatOptions) {
+        if let Some((turns, handle)) = self.folding.take_if(|(_, h)| h.is_finished()) {
+            match handle.await {
+                Ok(Ok(summary)) => {
+                    log::info!("summary: {}", summary);
+                    self.summary = summary;
+                }
+                r => {
+                    log::error!("summarize failed: {:?}", r);
+                    self.backlog.splice(0..0, turns);
+                }
+            }
+        }
+        if self.folding.is_some() || self.backlog.is_empty() {
+            return;
+        }
+
+        let turns = std::mem::take(&mut self.backlog);
+        let mut conversation = String::new();
+        if !self.summary.is_empty() {
+            conversation.push_str(&format!("已有的直播经过：\n{}\n\n", self.summary));
+        }
+        conversation.push_str("新的对话：\n");
+        for turn in &turns {
+            conversation.push_str(&format!("{}: {}\n", turn.role, turn.message));
+        }
+        let prompts = [
+            Content {
+                role: Role::System,
+                message: self.config.prompt.clone(),
+            },
+            Content {
+                role: Role::User,
+                message: conversation,
+            },
+        ];
+
+        let (llm_url, token) = (llm_url.to_string(), token.to_string());
+        let options = ChatOptions {
+            max_tokens: self.config.max_tokens,
+            ..options.clone()
+        };
+        let handle = tokio::spawn(async move {
+            let mut resp = llm_stable(&llm_url, &token, None, &options, prompts).await?;
+            let mut summary = String::new();
+            while let Some(chunk) = resp.next_chunk().await? {
+                summary.push_str(&chunk);
+            }
+            Ok(summary.trim().to_string())
+        });
+        self.folding = Some((turns, handle));
+    }
+}
+
+#[test]
+fn test_trim() {
+    let turn = |role: Role, message: &str| Content {
+        role,
+        message: message.to_string(),
+    };
+    let history = || {
+        LinkedList::from([
+            turn(Role::Assistant, "welcome"),
+            turn(Role::User, "a"),
+            turn(Role::Assistant, "reply a"),
+            turn(Role::User, &"spam ".repeat(100)),
+            turn(Role::Assistant, "reply b"),
+            turn(Role::User, "c"),
+        ])
+    };
+    let messages = |turns: &LinkedList<Content>| {
+        turns
+            .iter()
+            .map(|c| c.message.as_str())
+            .collect::<Vec<_>>()
+            .join("|")
+    };
+
+    let mut turns = history();
+    let evicted = trim(&mut turns, 4, None);
+    assert_eq!(
+        messages(&turns),
+        format!("{}|reply b|c", "spam ".repeat(100))
+    );
+    assert_eq!(evicted.len(), 3);
+
+    // the spammy batch goes with its reply, the pair before it too
+    let counter = TokenCounter::new("cl100k_base").unwrap();
+    let max_tokens = counter.messages(history().iter().skip(3)) - 1;
+    let budget = TokenBudget {
+        counter,
+        max_tokens,
+    };
+    let mut turns = history();
+    let evicted = trim(&mut turns, 100, Some(&budget));
+    assert_eq!(messages(&turns), "c");
+    assert_eq!(evicted.len(), 5);
+
+    // never below the newest message
+    let budget = TokenBudget {
+        counter: TokenCounter::new("o200k_base").unwrap(),
+        max_tokens: 0,
+    };
+    let mut turns = history();
+    trim(&mut turns, 100, Some(&budget));
+    assert_eq!(messages(&turns), "c");
+
+    assert!(TokenCounter::new("llama").is_err());
+}
+
+#[tokio::test]
+async fn test_summarizer() {
+    use axum::{routing::post, Json, Router};
+
+    let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let requests_ = requests.clone();
+    let llm = post(move |Json(body): Json<serde_json::Value>| async move {
+        let n = {
+            let mut requests = requests_.lock().unwrap();
+            requests.push((
+                body["messages"][1]["content"].as_str().unwrap().to_string(),
+                body["max_tokens"].clone(),
+            ));
+            requests.len()
+        };
+        let delta =
+            serde_json::json!({"choices": [{"delta": {"content": format!("summary {n}")}}]});
+        format!("data: {delta}\n\ndata: [DONE]\n\n")
+    });
+    let app = Router::new().route("/v1/chat/completions", llm);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let url = format!("http://{addr}/v1/chat/completions");
+    // tuned for short replies
+    let options = ChatOptions {
+        max_tokens: Some(64),
+        ..Default::default()
+    };
+    let turn = |role: Role, message: &str| Content {
+        role,
+        message: message.to_string(),
+    };
+    let mut summarizer = Summarizer::new(SummaryConfig {
+        prompt: "summarize".to_string(),
+        story_prompt: "so far: {summary}".to_string(),
+        max_tokens: Some(1024),
+    });
+    assert!(summarizer.message().is_none());
+
+    summarizer.evict(vec![
+        turn(Role::User, "bob: hi"),
+        turn(Role::Assistant, "hi bob"),
+    ]);
+    summarizer.poll(&url, "", &options).await;
+    // evicted while the first fold is running
+    summarizer.evict(vec![turn(Role::User, "alice: hello")]);
+    while summarizer.folding.is_some() || !summarizer.backlog.is_empty() {
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        summarizer.poll(&url, "", &options).await;
+    }
+
+    let requests = requests.lock().unwrap().clone();
+    assert_eq!(
+        requests[0].0,
+        "新的对话：\nuser: bob: hi\nassistant: hi bob\n"
+    );
+    assert_eq!(
+        requests[1].0,
+        "已有的直播经过：\nsummary 1\n\n新的对话：\nuser: alice: hello\n"
+    );
+    assert_eq!(requests[0].1, 1024);
+    let message = summarizer.message().unwrap();
+    assert_eq!(message.role, Role::System);
+    assert_eq!(message.message, "so far: summary 2");
+}
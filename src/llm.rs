@@ -1,6 +1,8 @@
 use bytes::Bytes;
 use reqwest::multipart::Part;
 
+use crate::sse::{SseDecoder, SseEvent};
+
 /// return: wav_audio: 16bit,32k,single-channel.
 pub async fn tts(tts_url: &str, speaker: &str, text: &str) -> anyhow::Result<Bytes> {
     let client = reqwest::Client::new();
@@ -23,14 +25,19 @@ pub async fn tts(tts_url: &str, speaker: &str, text: &str) -> anyhow::Result<Byt
     Ok(bytes)
 }
 
-// cargo test --package llm_streaming --bin llm_streaming -- llm::test_tts --exact --show-output
 #[tokio::test]
 async fn test_tts() {
-    let tts_url = "http://localhost:3000/tts";
+    let mock = crate::mock::MockServer::start().await;
     let speaker = "ht";
     let text = "你好，我是胡桃";
-    let wav_audio = tts(tts_url, speaker, text).await.unwrap();
-    std::fs::write("./resources/test/out.wav", wav_audio).unwrap();
+    let wav_audio = tts(&format!("{}/tts", mock.url), speaker, text)
+        .await
+        .unwrap();
+    assert!(crate::tts::wav_duration(&wav_audio).is_some());
+    assert_eq!(
+        *mock.state.speech.lock().unwrap(),
+        vec![(speaker.to_string(), text.to_string())]
+    );
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -58,16 +65,23 @@ impl FishTTSRequest {
     }
 }
 
-pub async fn fish_tts(token: &str, speaker: &str, text: &str) -> anyhow::Result<Bytes> {
+/// format: `wav`, `mp3`, `pcm` or `opus`.
+pub async fn fish_tts(
+    url: &str,
+    token: &str,
+    speaker: &str,
+    text: &str,
+    format: &str,
+) -> anyhow::Result<Bytes> {
     let client = reqwest::Client::new();
     let res = client
-        .post("https://api.fish.audio/v1/tts")
+        .post(url)
         .header("content-type", "application/msgpack")
         .header("authorization", &format!("Bearer {}", token))
         .body(rmp_serde::to_vec_named(&FishTTSRequest::new(
             speaker.to_string(),
             text.to_string(),
-            "wav".to_string(),
+            format.to_string(),
         ))?)
         .send()
         .await?;
@@ -86,34 +100,78 @@ pub async fn fish_tts(token: &str, speaker: &str, text: &str) -> anyhow::Result<
 
 #[tokio::test]
 async fn test_fish_tts() {
-    let token = std::env::var("FISH_API_KEY").unwrap();
+    let mock = crate::mock::MockServer::start().await;
+    let url = format!("{}/v1/tts", mock.url);
     let speaker = "256e1a3007a74904a91d132d1e9bf0aa";
     let text = "hello fish";
 
-    let r = rmp_serde::to_vec_named(&FishTTSRequest::new(
-        speaker.to_string(),
-        text.to_string(),
-        "wav".to_string(),
-    ));
-    println!("{:x?}", r);
+    let wav_audio = fish_tts(&url, "token", speaker, text, "wav").await.unwrap();
+    assert!(crate::tts::wav_duration(&wav_audio).is_some());
+    assert_eq!(
+        *mock.state.speech.lock().unwrap(),
+        vec![(speaker.to_string(), text.to_string())]
+    );
+}
+
+#[derive(Debug, serde::Serialize)]
+struct OpenAITTSRequest<'a> {
+    model: &'a str,
+    input: &'a str,
+    voice: &'a str,
+    response_format: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    speed: Option<f32>,
+}
 
-    let wav_audio = fish_tts(&token, speaker, text).await.unwrap();
-    std::fs::write("./resources/test/out.wav", wav_audio).unwrap();
+/// OpenAI compatible `/v1/audio/speech`.
+pub async fn openai_tts(
+    speech_url: &str,
+    api_key: &str,
+    model: &str,
+    voice: &str,
+    text: &str,
+    response_format: &str,
+    speed: Option<f32>,
+) -> anyhow::Result<Bytes> {
+    let client = reqwest::Client::new();
+    let mut builder = client.post(speech_url).json(&OpenAITTSRequest {
+        model,
+        input: text,
+        voice,
+        response_format,
+        speed,
+    });
+    if !api_key.is_empty() {
+        builder = builder.bearer_auth(api_key);
+    }
+    let res = builder.send().await?;
+    let status = res.status();
+    if !status.is_success() {
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "tts failed, status:{}, body:{}",
+            status,
+            body
+        ));
+    }
+    let bytes = res.bytes().await?;
+    Ok(bytes)
 }
 
-#[allow(unused)]
 #[derive(Debug, serde::Deserialize)]
 struct AsrResult {
     #[serde(default)]
     text: String,
 }
 
-#[allow(unused)]
 impl AsrResult {
+    /// `[00:00:00.000 --> 00:00:02.000] text` lines, or plain text as
+    /// returned by OpenAI style whisper servers.
     fn parse_text(self) -> Vec<String> {
         let mut texts = vec![];
         for line in self.text.lines() {
-            if let Some((_, t)) = line.split_once("] ") {
+            let t = line.split_once("] ").map(|(_, t)| t).unwrap_or(line).trim();
+            if !t.is_empty() {
                 texts.push(t.to_string());
             }
         }
@@ -121,7 +179,6 @@ impl AsrResult {
     }
 }
 
-#[allow(unused)]
 /// wav_audio: 16bit,16k,single-channel.
 pub async fn asr(asr_url: &str, lang: &str, wav_audio: Vec<u8>) -> anyhow::Result<Vec<String>> {
     let client = reqwest::Client::new();
@@ -132,17 +189,65 @@ pub async fn asr(asr_url: &str, lang: &str, wav_audio: Vec<u8>) -> anyhow::Resul
     }
 
     let res = client.post(asr_url).multipart(form).send().await?;
+    let status = res.status();
+    if !status.is_success() {
+        let body = res.text().await?;
+        return Err(anyhow::anyhow!(
+            "asr failed, status:{}, body:{}",
+            status,
+            body
+        ));
+    }
     let asr_result: AsrResult = res.json().await?;
     Ok(asr_result.parse_text())
 }
 
+#[test]
+fn test_asr_parse_text() {
+    let r = AsrResult {
+        text: "[00:00:00.000 --> 00:00:01.000] 你好\n[00:00:01.000 --> 00:00:02.000] 世界\n"
+            .to_string(),
+    };
+    assert_eq!(r.parse_text(), vec!["你好", "世界"]);
+
+    let r = AsrResult {
+        text: " Hello there. ".to_string(),
+    };
+    assert_eq!(r.parse_text(), vec!["Hello there."]);
+}
+
 #[tokio::test]
 async fn test_asr() {
-    let asr_url = "https://whisper.gaia.domains/v1/audio/transcriptions";
-    let lang = "zh";
-    let wav_audio = std::fs::read("./resources/test/out.wav").unwrap();
-    let text = asr(asr_url, lang, wav_audio).await.unwrap();
-    println!("ASR result: {:?}", text);
+    let mock = crate::mock::MockServer::start().await;
+    *mock.state.transcript.lock().unwrap() = "[00:00:00.000 --> 00:00:01.000] 你好\n".to_string();
+    let asr_url = format!("{}/v1/audio/transcriptions", mock.url);
+    let wav_audio = crate::tts::silence_wav(1.0).to_vec();
+    let text = asr(&asr_url, "zh", wav_audio).await.unwrap();
+    assert_eq!(text, vec!["你好"]);
+}
+
+/// OpenAI chat completion parameters, sent only when set.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct ChatOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    /// Provider specific fields, merged into the top level of the request body.
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -152,36 +257,99 @@ pub struct StableLlmRequest {
     #[serde(skip_serializing_if = "String::is_empty")]
     chat_id: String,
     messages: Vec<llm::Content>,
+    #[serde(flatten)]
+    options: ChatOptions,
+}
+
+impl StableLlmRequest {
+    /// Request body, with `options.extra` lifted to the top level.
+    /// Extra fields never override the ones above.
+    fn to_body(&self) -> anyhow::Result<serde_json::Value> {
+        let mut body = serde_json::to_value(self)?;
+        if let Some(obj) = body.as_object_mut() {
+            if let Some(serde_json::Value::Object(extra)) = obj.remove("extra") {
+                for (k, v) in extra {
+                    obj.entry(k).or_insert(v);
+                }
+            }
+        }
+        Ok(body)
+    }
 }
 
 pub struct StableLlmResponse {
+    /// Id of the request in the session recording.
+    request: u64,
     stopped: bool,
     response: reqwest::Response,
+    decoder: SseDecoder,
     string_buffer: String,
+    /// When the request was sent.
+    started: std::time::Instant,
+    on_first_token: Option<Box<dyn FnOnce(std::time::Duration) + Send>>,
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
 struct StableStreamChunkChoices {
+    #[serde(default)]
     delta: llm::Content,
-    finish_reason: Option<String>,
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
 struct StableStreamChunk {
+    #[serde(default)]
     choices: Vec<StableStreamChunkChoices>,
+    #[serde(default)]
+    error: Option<serde_json::Value>,
+}
+
+/// Content delta of one stream event, `None` once the stream is `[DONE]`.
+fn event_delta(event: &SseEvent) -> anyhow::Result<Option<String>> {
+    if event.data.trim() == "[DONE]" {
+        return Ok(None);
+    }
+    if event.event == "error" {
+        return Err(anyhow::anyhow!("llm stream error: {}", event.data));
+    }
+
+    let chunk = match serde_json::from_str::<StableStreamChunk>(&event.data) {
+        Ok(chunk) => chunk,
+        Err(e) => {
+            log::warn!("skip bad llm stream chunk {}: {}", event.data, e);
+            return Ok(Some(String::new()));
+        }
+    };
+    if let Some(error) = chunk.error {
+        return Err(anyhow::anyhow!("llm stream error: {}", error));
+    }
+    Ok(Some(
+        chunk
+            .choices
+            .into_iter()
+            .next()
+            .map(|c| c.delta.message)
+            .unwrap_or_default(),
+    ))
 }
 
 impl StableLlmResponse {
     const CHUNK_SIZE: usize = 50;
 
+    /// Calls `f` with the time to the first token as soon as it arrives,
+    /// not once a whole sentence is read.
+    pub fn on_first_token<F: FnOnce(std::time::Duration) + Send + 'static>(mut self, f: F) -> Self {
+        self.on_first_token = Some(Box::new(f));
+        self
+    }
+
     fn return_string_buffer(&mut self) -> anyhow::Result<Option<String>> {
         self.stopped = true;
         if !self.string_buffer.is_empty() {
             let mut new_str = String::new();
             std::mem::swap(&mut new_str, &mut self.string_buffer);
-            return Ok(Some(new_str));
+            Ok(Some(new_str))
         } else {
-            return Ok(None);
+            Ok(None)
         }
     }
 
@@ -189,7 +357,7 @@ impl StableLlmResponse {
         let mut ret = s;
 
         loop {
-            if let Some(i) = ret.find(&['.', '!', '?', ';', '。', '！', '？', '；', '\n']) {
+            if let Some(i) = ret.find(['.', '!', '?', ';', '。', '！', '？', '；', '\n']) {
                 let (chunk, ret_) = if ret.is_char_boundary(i + 1) {
                     ret.split_at(i + 1)
                 } else {
@@ -212,30 +380,44 @@ impl StableLlmResponse {
     }
 
     pub async fn next_chunk(&mut self) -> anyhow::Result<Option<String>> {
+        let chunk = self.read_chunk().await?;
+        if let Some(text) = &chunk {
+            crate::recorder::record(crate::recorder::Record::Chunk {
+                request: self.request,
+                text: text.clone(),
+            });
+        }
+        Ok(chunk)
+    }
+
+    async fn read_chunk(&mut self) -> anyhow::Result<Option<String>> {
         loop {
             if self.stopped {
-                return Ok(None);
-            }
-
-            let body = self.response.chunk().await?;
-            if body.is_none() {
                 return self.return_string_buffer();
             }
-            let body = body.unwrap();
-            let body = String::from_utf8_lossy(&body);
 
-            let mut chunks = String::new();
-            body.split("data: ").for_each(|s| {
-                if s.is_empty() || s.starts_with("[DONE]") {
-                    return;
+            let events = match self.response.chunk().await? {
+                Some(body) => self.decoder.push(&body),
+                None => {
+                    self.stopped = true;
+                    self.decoder.finish().into_iter().collect()
                 }
+            };
 
-                if let Ok(chunk) = serde_json::from_str::<StableStreamChunk>(s.trim()) {
-                    if !chunk.choices[0].finish_reason.is_some() {
-                        chunks.push_str(&chunk.choices[0].delta.message);
+            let mut chunks = String::new();
+            for event in &events {
+                match event_delta(event)? {
+                    Some(delta) => {
+                        if !delta.is_empty() {
+                            if let Some(f) = self.on_first_token.take() {
+                                f(self.started.elapsed());
+                            }
+                        }
+                        chunks.push_str(&delta)
                     }
+                    None => self.stopped = true,
                 }
-            });
+            }
 
             if let Some(new_str) = self.push_str(&chunks) {
                 return Ok(Some(new_str));
@@ -244,16 +426,18 @@ impl StableLlmResponse {
     }
 }
 
+#[allow(clippy::module_inception)]
 pub mod llm {
     use std::fmt::Display;
 
-    #[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
     pub enum Role {
         #[serde(rename = "system")]
         System,
         #[serde(rename = "user")]
         User,
         #[serde(rename = "assistant")]
+        #[default]
         Assistant,
     }
 
@@ -274,13 +458,7 @@ pub mod llm {
         }
     }
 
-    impl Default for Role {
-        fn default() -> Self {
-            Self::Assistant
-        }
-    }
-
-    #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+    #[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
     pub struct Content {
         #[serde(default)]
         pub role: Role,
@@ -297,10 +475,11 @@ pub mod llm {
     }
 }
 
-pub async fn llm_stable<'p, I: IntoIterator<Item = C>, C: AsRef<llm::Content>>(
+pub async fn llm_stable<I: IntoIterator<Item = C>, C: AsRef<llm::Content>>(
     llm_url: &str,
     token: &str,
     chat_id: Option<String>,
+    options: &ChatOptions,
     prompts: I,
 ) -> anyhow::Result<StableLlmResponse> {
     let messages = prompts
@@ -315,14 +494,21 @@ pub async fn llm_stable<'p, I: IntoIterator<Item = C>, C: AsRef<llm::Content>>(
         response_builder = response_builder.header(reqwest::header::AUTHORIZATION, token);
     };
 
-    let response = response_builder
-        .json(&StableLlmRequest {
-            stream: true,
-            chat_id: chat_id.unwrap_or_default(),
-            messages,
-        })
-        .send()
-        .await?;
+    let request = StableLlmRequest {
+        stream: true,
+        chat_id: chat_id.unwrap_or_default(),
+        messages,
+        options: options.clone(),
+    };
+    let body = request.to_body()?;
+    let request = crate::recorder::next_request();
+    crate::recorder::record(crate::recorder::Record::Prompt {
+        request,
+        url: llm_url.to_string(),
+        body: body.clone(),
+    });
+    let started = std::time::Instant::now();
+    let response = response_builder.json(&body).send().await?;
 
     let state = response.status();
     if !state.is_success() {
@@ -335,17 +521,81 @@ pub async fn llm_stable<'p, I: IntoIterator<Item = C>, C: AsRef<llm::Content>>(
     }
 
     Ok(StableLlmResponse {
+        request,
         stopped: false,
         response,
+        decoder: SseDecoder::new(),
         string_buffer: String::new(),
+        started,
+        on_first_token: None,
     })
 }
 
-// cargo test --package llm_streaming --bin llm_streaming -- llm::test_statble_llm --exact --show-output
+#[test]
+fn test_event_delta() {
+    let event = |event: &str, data: &str| SseEvent {
+        event: event.to_string(),
+        data: data.to_string(),
+        id: None,
+    };
+
+    let delta =
+        r#"{"choices":[{"delta":{"role":"assistant","content":"hi"},"finish_reason":null}]}"#;
+    assert_eq!(
+        event_delta(&event("message", delta)).unwrap().unwrap(),
+        "hi"
+    );
+    let usage = r#"{"choices":[],"usage":{"total_tokens":3}}"#;
+    assert_eq!(event_delta(&event("message", usage)).unwrap().unwrap(), "");
+    assert!(event_delta(&event("message", "[DONE]")).unwrap().is_none());
+
+    let error = r#"{"error":{"message":"overloaded","type":"server_error"}}"#;
+    assert!(event_delta(&event("message", error)).is_err());
+    assert!(event_delta(&event("error", error)).is_err());
+}
+
+#[test]
+fn test_request_body() {
+    let options: ChatOptions = toml::from_str(
+        r#"
+        model = "llama"
+        temperature = 0.5
+        max_tokens = 256
+        stop = ["\n\n"]
+        seed = 42
+        [extra]
+        stream = false
+        chat_template_kwargs = { enable_thinking = false }
+        "#,
+    )
+    .unwrap();
+
+    let request = StableLlmRequest {
+        stream: true,
+        chat_id: String::new(),
+        messages: vec![],
+        options,
+    };
+    assert_eq!(
+        request.to_body().unwrap(),
+        serde_json::json!({
+            "stream": true,
+            "messages": [],
+            "model": "llama",
+            "temperature": 0.5,
+            "max_tokens": 256,
+            "stop": ["\n\n"],
+            "seed": 42,
+            "chat_template_kwargs": {"enable_thinking": false},
+        })
+    );
+}
+
 #[tokio::test]
 async fn test_statble_llm() {
-    env_logger::init();
-    let token = std::env::var("API_KEY").ok().map(|k| format!("Bearer {k}"));
+    let mock = crate::mock::MockServer::start().await;
+    let answer = "鸡和兔在同一个笼子里，数头和脚就能算出各有几只。";
+    mock.reply(answer);
 
     let prompts = vec![
         llm::Content {
@@ -358,33 +608,60 @@ async fn test_statble_llm() {
         },
     ];
 
-    let token = if let Some(t) = token.as_ref() {
-        t.as_str()
-    } else {
-        ""
-    };
-
     let mut resp = llm_stable(
-        "https://llama70b.gaia.domains/v1/chat/completions",
-        token,
+        &format!("{}/v1/chat/completions", mock.url),
+        "",
         None,
-        prompts,
+        &ChatOptions::default(),
+        prompts.clone(),
     )
     .await
     .unwrap();
 
-    loop {
-        match resp.next_chunk().await {
-            Ok(Some(chunk)) => {
-                println!("{}", chunk);
-            }
-            Ok(None) => {
-                break;
-            }
-            Err(e) => {
-                println!("error: {:#?}", e);
-                break;
-            }
-        }
+    let mut reply = String::new();
+    while let Some(chunk) = resp.next_chunk().await.unwrap() {
+        reply.push_str(&chunk);
     }
+    assert_eq!(reply, answer);
+
+    let requests = mock.state.requests.lock().unwrap();
+    assert_eq!(requests[0]["messages"], serde_json::json!(prompts));
+}
+
+#[tokio::test]
+async fn test_first_token() {
+    use axum::{routing::post, Router};
+    use futures_util::StreamExt;
+
+    // half a sentence, then nothing
+    let llm = post(|| async {
+        let delta = serde_json::json!({"choices": [{"delta": {"content": "鸡和兔"}}]});
+        let first = Ok::<_, std::convert::Infallible>(format!("data: {delta}\n\n"));
+        let sse = futures_util::stream::iter([first]).chain(futures_util::stream::pending());
+        axum::body::Body::from_stream(sse)
+    });
+    let app = Router::new().route("/v1/chat/completions", llm);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let mut resp = llm_stable(
+        &format!("http://{addr}/v1/chat/completions"),
+        "",
+        None,
+        &ChatOptions::default(),
+        Vec::<llm::Content>::new(),
+    )
+    .await
+    .unwrap()
+    .on_first_token(move |latency| {
+        let _ = tx.send(latency);
+    });
+
+    // no whole sentence yet, but the first token is in
+    let chunk = tokio::time::timeout(std::time::Duration::from_millis(200), resp.next_chunk());
+    assert!(chunk.await.is_err());
+    let latency = rx.await.unwrap();
+    assert!(latency < std::time::Duration::from_millis(200));
 }
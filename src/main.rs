@@ -1,36 +1,221 @@
 mod app;
 mod config;
+mod history;
 mod llm;
+mod memory;
+mod metrics;
+#[cfg(test)]
+mod mock;
+mod recorder;
+mod replay;
+mod sse;
+mod state;
+mod status;
 mod stream_platform;
+mod supervisor;
+mod tts;
+mod util;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    let config_path = std::env::args().nth(1).unwrap_or("config.toml".to_string());
+    // `llm_streaming [config.toml]` or `llm_streaming replay [config.toml]`
+    let mut args = std::env::args().skip(1).peekable();
+    let replay_mode = args.next_if(|arg| arg == "replay").is_some();
+    let config_path = args.next().unwrap_or("config.toml".to_string());
     let config_str = std::fs::read_to_string(config_path).unwrap();
-    let config: config::Config = toml::from_str(&config_str).unwrap();
+    let mut config: config::Config = toml::from_str(&config_str).unwrap();
     log::info!("{:#?}", config);
 
+    let replay = if replay_mode {
+        let replay_config = config.replay.clone().expect("[replay] in config");
+        let replay = replay::Replay::load(replay_config).expect("replay");
+        if let Some(url) = replay.serve_llm().await.expect("recorded llm") {
+            config.llm.llm_chat_url = url;
+        }
+        Some(std::sync::Arc::new(replay))
+    } else {
+        None
+    };
+
+    if let Some(recorder) = &config.recorder {
+        if let Some(replay) = &replay {
+            replay.check_recorder(recorder).expect("recorder");
+        }
+        recorder::init(recorder).expect("recorder");
+    }
+
     let listener = tokio::net::TcpListener::bind(&config.listen).await.unwrap();
 
+    let state = config.state.map(|state| {
+        let state = std::sync::Arc::new(state::StateStore::load(state).expect("state"));
+        tokio::spawn(state.clone().run_timer());
+        state
+    });
+
+    let (supervisor, mut fatal_rx) = supervisor::Supervisor::new(config.supervisor.clone());
+    let status = std::sync::Arc::new(status::Status::default());
+
     let (stream_tx, stream_rx) = tokio::sync::mpsc::unbounded_channel();
-    match config.platform {
-        config::StreamPlatFormConfig::Bilibili(bilibili) => {
-            let max_conment = bilibili.max_comment;
-            let client = stream_platform::bilibili::BiliLiveClient::from_config(bilibili)
-                .expect("bilibili client");
-            tokio::spawn(stream_platform::llm_loop(max_conment, stream_rx, client));
+    let stream_rx = std::sync::Arc::new(tokio::sync::Mutex::new(stream_rx));
+    let platform_config = config.platform.clone();
+    let state_ = state.clone();
+    let status_ = status.clone();
+    let replay_ = replay.clone();
+    supervisor.spawn("llm_loop", move || {
+        let stream_rx = stream_rx.clone();
+        let platform_config = platform_config.clone();
+        let state = state_.clone();
+        let status = status_.clone();
+        let replay = replay_.clone();
+        async move {
+            let (platforms, max_comment) = match replay {
+                Some(replay) => {
+                    let max_comment = platform_config.iter().map(|p| p.max_comment()).max();
+                    (replay.platforms(status.clone()), max_comment.unwrap_or(20))
+                }
+                None => connect_platforms(platform_config, status.clone()).await?,
+            };
+            let mut stream_rx = stream_rx.lock().await;
+            stream_platform::llm_loop(max_comment, &mut stream_rx, platforms, state, status).await
         }
-        config::StreamPlatFormConfig::Restream(restream) => {
-            let max_conment = restream.max_comment;
-            let client = stream_platform::restream::RestreamChat::from_config(restream)
-                .await
-                .expect("restream chat");
-            tokio::spawn(stream_platform::llm_loop(max_conment, stream_rx, client));
+    });
+
+    log::info!("Start on {}", &config.listen);
+    let tts = match replay.as_ref().and_then(|r| r.tts(&config.tts)) {
+        Some(tts) => tts,
+        None => tts::TtsRegistry::default()
+            .build(&config.tts)
+            .expect("tts engine"),
+    };
+    let llm_agent = app::LlmAgent::new(
+        std::sync::Arc::new(app::Downstream::new(config.downstream)),
+        tts,
+        config.tts_concurrency,
+        status.clone(),
+    );
+
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let shutdown = app::Shutdown {
+        signal: shutdown_rx.clone(),
+        config: config.shutdown.clone(),
+    };
+    let (app, handler) = app::router(
+        config.llm,
+        llm_agent,
+        config.asr,
+        state.clone(),
+        stream_tx,
+        shutdown,
+        &supervisor,
+    );
+
+    let signal_task = tokio::spawn(async move {
+        let replay_finished = async {
+            match &replay {
+                Some(replay) => replay.finished(&status).await,
+                None => std::future::pending().await,
+            }
+        };
+        let failed = tokio::select! {
+            _ = shutdown_signal() => None,
+            Some(name) = fatal_rx.recv() => Some(name),
+            _ = replay_finished => {
+                log::info!("replay finished");
+                None
+            }
+        };
+        log::info!("shutting down");
+        let _ = shutdown_tx.send(true);
+        failed
+    });
+    let mut signal = shutdown_rx;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            let _ = signal.wait_for(|s| *s).await;
+        })
+        .await
+        .unwrap();
+
+    let timeout = std::time::Duration::from_secs(config.shutdown.timeout_secs);
+    if tokio::time::timeout(timeout, handler).await.is_err() {
+        log::warn!("agent didn't stop within {:?}", timeout);
+    }
+    if let Some(state) = state {
+        if let Err(e) = state.save() {
+            log::error!("save state failed: {:?}", e);
         }
     }
+    if let Ok(Some(name)) = signal_task.await {
+        log::error!("{} can't recover: {:#?}", name, supervisor.health());
+        std::process::exit(1);
+    }
+    log::info!("bye");
+}
 
-    log::info!("Start on {}", &config.listen);
-    let app = app::router(config.llm, config.tts, config.downstream, stream_tx);
-    axum::serve(listener, app).await.unwrap();
+async fn connect_platforms(
+    configs: Vec<config::StreamPlatFormConfig>,
+    status: std::sync::Arc<status::Status>,
+) -> anyhow::Result<(stream_platform::multi::MultiPlatform, usize)> {
+    use status::PlatformState;
+
+    config::StreamPlatFormConfig::check_names(&configs)?;
+    for platform in &configs {
+        status.platform(platform.name(), PlatformState::Connecting);
+    }
+    let mut platforms = stream_platform::multi::MultiPlatform::with_status(status.clone());
+    let mut max_comment = 0;
+    for platform in configs {
+        let name = platform.name().to_string();
+        let name = name.as_str();
+        let kind = platform.kind();
+        max_comment = max_comment.max(platform.max_comment());
+        let r = match platform {
+            config::StreamPlatFormConfig::Bilibili(bilibili) => {
+                stream_platform::bilibili::BiliLiveClient::from_config(bilibili)
+                    .map(|client| platforms.push_kind(name, kind, client))
+            }
+            config::StreamPlatFormConfig::Restream(restream) => {
+                stream_platform::restream::RestreamChat::from_config(restream)
+                    .await
+                    .map(|client| platforms.push_kind(name, kind, client))
+            }
+            config::StreamPlatFormConfig::Twitch(twitch) => {
+                stream_platform::twitch::TwitchChat::from_config(twitch)
+                    .await
+                    .map(|client| platforms.push_kind(name, kind, client))
+            }
+            config::StreamPlatFormConfig::Console(_) => {
+                platforms.push_kind(name, kind, stream_platform::console::ConsoleChat::stdin());
+                Ok(())
+            }
+            config::StreamPlatFormConfig::File(file) => {
+                stream_platform::console::ConsoleChat::from_config(file)
+                    .await
+                    .map(|client| platforms.push_kind(name, kind, client))
+            }
+        };
+        if let Err(e) = r {
+            status.platform(name, PlatformState::Disconnected);
+            status.error(name, &e);
+            return Err(e);
+        }
+    }
+    Ok((platforms, max_comment))
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = term.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }
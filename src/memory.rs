@@ -0,0 +1,407 @@
+//! What the agent remembers about viewers, across streams.
+
+use std::{
+    collections::{HashMap, LinkedList},
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use crate::{
+    config::MemoryConfig,
+    llm::{
+        llm::{Content, Role},
+        llm_stable, ChatOptions,
+    },
+    stream_platform::{PlatformEvent, SteamEvent},
+};
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Viewer {
+    pub name: String,
+    /// unix seconds
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub messages: u64,
+    #[serde(default)]
+    pub facts: Vec<String>,
+}
+
+/// Viewers keyed by `source:user id`, kept in a JSON file.
+///
+/// Changes are written by `run_timer`, and when the memory is dropped.
+pub struct ViewerMemory {
+    config: MemoryConfig,
+    viewers: HashMap<String, Viewer>,
+    /// Changed since the last save.
+    dirty: bool,
+}
+
+/// `None` for viewers that can't be told apart, e.g. bilibili guests are all uid 0.
+fn key(event: &PlatformEvent) -> Option<String> {
+    let id = &event.event.user().id;
+    if id.is_empty() || id == "0" {
+        return None;
+    }
+    // events recorded before there was a source
+    let source = match event.source.as_str() {
+        "" => &event.platform,
+        source => source,
+    };
+    Some(format!("{source}:{id}"))
+}
+
+fn write(path: &str, data: &[u8]) -> anyhow::Result<()> {
+    let tmp = format!("{path}.tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// What the viewer said, if anything.
+fn message(event: &SteamEvent) -> Option<&str> {
+    match event {
+        SteamEvent::Comment { content, .. } | SteamEvent::SuperChat { content, .. } => {
+            Some(content)
+        }
+        SteamEvent::Command { command, .. } => Some(command),
+        _ => None,
+    }
+}
+
+impl ViewerMemory {
+    /// Starts empty if the file doesn't exist yet.
+    pub fn load(config: MemoryConfig) -> anyhow::Result<Self> {
+        let viewers = match std::fs::read(&config.path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        log::info!("{} viewers remembered", viewers.len());
+        Ok(Self {
+            config,
+            viewers,
+            dirty: false,
+        })
+    }
+
+    pub fn save(&mut self) -> anyhow::Result<()> {
+        write(
+            &self.config.path,
+            &serde_json::to_vec_pretty(&self.viewers)?,
+        )?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Writes the changes every `interval_secs` off the runtime, until the memory is dropped.
+    pub async fn run_timer(memory: Weak<Mutex<ViewerMemory>>) {
+        let period = match memory.upgrade() {
+            Some(memory) => Duration::from_secs(memory.lock().unwrap().config.interval_secs.max(1)),
+            None => return,
+        };
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            // held until written, so the save on drop comes after this one
+            let Some(memory) = memory.upgrade() else {
+                return;
+            };
+            let (path, data) = {
+                let mut memory = memory.lock().unwrap();
+                if !memory.dirty {
+                    continue;
+                }
+                memory.dirty = false;
+                (
+                    memory.config.path.clone(),
+                    serde_json::to_vec_pretty(&memory.viewers),
+                )
+            };
+            let r = match data {
+                Ok(data) => tokio::task::spawn_blocking(move || write(&path, &data))
+                    .await
+                    .unwrap_or_else(|e| Err(e.into())),
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = r {
+                log::error!("save viewer memory failed: {:?}", e);
+                memory.lock().unwrap().dirty = true;
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn get(&self, source: &str, id: &str) -> Option<&Viewer> {
+        self.viewers.get(&format!("{source}:{id}"))
+    }
+
+    /// Counts the events in, and returns what is remembered about the
+    /// viewers among them who spoke before.
+    pub fn record(&mut self, events: &LinkedList<PlatformEvent>, now: u64) -> String {
+        let mut notes = String::new();
+        let mut in_batch = vec![];
+        for event in events {
+            if matches!(event.event, SteamEvent::Enter { .. }) {
+                continue;
+            }
+            let Some(key) = key(event) else {
+                continue;
+            };
+            let user = event.event.user();
+            self.dirty = true;
+            let viewer = self.viewers.entry(key.clone()).or_insert_with(|| Viewer {
+                first_seen: now,
+                ..Default::default()
+            });
+            viewer.name = user.name.clone();
+            viewer.last_seen = now;
+            if message(&event.event).is_none() {
+                continue;
+            }
+
+            // only viewers who spoke before this batch
+            if !in_batch.contains(&key) && viewer.messages > 0 {
+                let days = now.saturating_sub(viewer.first_seen) / (24 * 3600);
+                let since = if days == 0 {
+                    "今天".to_string()
+                } else {
+                    format!("{days}天前")
+                };
+                notes.push_str(&format!(
+                    "{}：{}第一次来，发过{}条评论。",
+                    user.name, since, viewer.messages
+                ));
+                if !viewer.facts.is_empty() {
+                    notes.push_str(&viewer.facts.join("；"));
+                }
+                notes.push('\n');
+            }
+            if !in_batch.contains(&key) {
+                in_batch.push(key);
+            }
+            viewer.messages += 1;
+        }
+
+        if notes.is_empty() {
+            notes
+        } else {
+            format!("以下是你记得的观众信息：\n{notes}")
+        }
+    }
+
+    fn remember(&mut self, key: &str, fact: String) {
+        let Some(viewer) = self.viewers.get_mut(key) else {
+            return;
+        };
+        if viewer.facts.contains(&fact) {
+            return;
+        }
+        viewer.facts.push(fact);
+        let over = viewer.facts.len().saturating_sub(self.config.max_facts);
+        viewer.facts.drain(..over);
+        self.dirty = true;
+    }
+}
+
+impl Drop for ViewerMemory {
+    fn drop(&mut self) {
+        if self.dirty {
+            if let Err(e) = self.save() {
+                log::error!("save viewer memory failed: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Asks the LLM for facts about the viewers who spoke in `events`, in the background.
+pub fn learn(
+    memory: Arc<Mutex<ViewerMemory>>,
+    events: &LinkedList<PlatformEvent>,
+    llm_url: &str,
+    token: &str,
+    options: &ChatOptions,
+) {
+    let prompt = {
+        let memory = memory.lock().unwrap();
+        if memory.config.max_facts == 0 {
+            return;
+        }
+        memory.config.facts_prompt.clone()
+    };
+
+    let mut keys: Vec<String> = vec![];
+    let mut comments = String::new();
+    for event in events {
+        let (Some(message), Some(key)) = (message(&event.event), key(event)) else {
+            continue;
+        };
+        let i = keys.iter().position(|k| *k == key).unwrap_or_else(|| {
+            keys.push(key);
+            keys.len() - 1
+        });
+        comments.push_str(&format!("{i}. {}: {message}\n", event.event.user()));
+    }
+    if keys.is_empty() {
+        return;
+    }
+
+    let prompts = [
+        Content {
+            role: Role::System,
+            message: prompt,
+        },
+        Content {
+            role: Role::User,
+            message: comments,
+        },
+    ];
+    let (llm_url, token, options) = (llm_url.to_string(), token.to_string(), options.clone());
+    tokio::spawn(async move {
+        let facts = async {
+            let mut resp = llm_stable(&llm_url, &token, None, &options, prompts).await?;
+            let mut facts = String::new();
+            while let Some(chunk) = resp.next_chunk().await? {
+                facts.push_str(&chunk);
+            }
+            anyhow::Ok(facts)
+        };
+        let facts = match facts.await {
+            Ok(facts) => facts,
+            Err(e) => {
+                log::warn!("learn viewer facts failed: {:?}", e);
+                return;
+            }
+        };
+
+        let mut memory = memory.lock().unwrap();
+        for line in facts.lines() {
+            let Some((i, fact)) = line.split_once([':', '：']) else {
+                continue;
+            };
+            let (Ok(i), fact) = (i.trim().parse::<usize>(), fact.trim()) else {
+                continue;
+            };
+            if let (Some(key), false) = (keys.get(i), fact.is_empty()) {
+                log::info!("remember {}: {}", key, fact);
+                memory.remember(key, fact.to_string());
+            }
+        }
+    });
+}
+
+#[tokio::test]
+async fn test_viewer_memory() {
+    use crate::stream_platform::User;
+    use axum::{routing::post, Json, Router};
+
+    let llm = post(|Json(body): Json<serde_json::Value>| async move {
+        assert_eq!(
+            body["messages"][1]["content"],
+            "0. bob: I'm from Paris\n1. alice: hi\n0. bob: I love cats\n"
+        );
+        let delta = serde_json::json!({"choices": [{"delta": {"content": "0: lives in Paris\n0：loves cats\nnothing about 1\n"}}]});
+        format!("data: {delta}\n\ndata: [DONE]\n\n")
+    });
+    let app = Router::new().route("/v1/chat/completions", llm);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let dir = std::env::temp_dir().join(format!("viewer_memory_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = MemoryConfig {
+        path: dir.join("viewers.json").to_string_lossy().to_string(),
+        max_facts: 20,
+        facts_prompt: "facts".to_string(),
+        interval_secs: 1,
+    };
+    let comment = |id: u64, name: &str, content: &str| PlatformEvent {
+        platform: "twitch".to_string(),
+        source: "twitch".to_string(),
+        event: SteamEvent::Comment {
+            user: User::new(id, name),
+            content: content.to_string(),
+        },
+    };
+    let events = LinkedList::from([
+        comment(1, "bob", "I'm from Paris"),
+        comment(2, "alice", "hi"),
+        comment(1, "bob", "I love cats"),
+    ]);
+
+    let memory = Arc::new(Mutex::new(ViewerMemory::load(config.clone()).unwrap()));
+    let notes = memory.lock().unwrap().record(&events, 1000);
+    assert_eq!(notes, "");
+    // written later, not by `record`
+    assert!(!std::path::Path::new(&config.path).exists());
+    tokio::spawn(ViewerMemory::run_timer(Arc::downgrade(&memory)));
+    learn(
+        memory.clone(),
+        &events,
+        &format!("http://{addr}/v1/chat/completions"),
+        "",
+        &ChatOptions::default(),
+    );
+    while memory.lock().unwrap().viewers["twitch:1"].facts.len() < 2 {
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+    while !std::fs::read_to_string(&config.path)
+        .unwrap_or_default()
+        .contains("loves cats")
+    {
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+
+    // the next stream, bob is renamed
+    let mut memory = ViewerMemory::load(config.clone()).unwrap();
+    let events = LinkedList::from([
+        comment(1, "bobby", "back again"),
+        comment(3, "carol", "hey"),
+    ]);
+    let notes = memory.record(&events, 1000 + 2 * 24 * 3600);
+    assert_eq!(
+        notes,
+        "以下是你记得的观众信息：\nbobby：2天前第一次来，发过2条评论。lives in Paris；loves cats\n"
+    );
+    let bob = memory.get("twitch", "1").unwrap();
+    assert_eq!((bob.name.as_str(), bob.messages), ("bobby", 3));
+
+    // guests and viewers only passing by aren't remembered
+    let guest = |id: u64| PlatformEvent {
+        platform: "bilibili".to_string(),
+        source: "bilibili".to_string(),
+        event: SteamEvent::Comment {
+            user: User::new(id, "guest"),
+            content: "hi".to_string(),
+        },
+    };
+    let enter = PlatformEvent {
+        platform: "twitch".to_string(),
+        source: "twitch".to_string(),
+        event: SteamEvent::Enter {
+            user: User::new(4, "dave"),
+        },
+    };
+    memory.record(
+        &LinkedList::from([guest(0), enter, comment(1, "bobby", "bye")]),
+        2000,
+    );
+    assert_eq!(memory.viewers.len(), 3);
+    assert!(memory.get("bilibili", "0").is_none());
+    assert!(memory.get("twitch", "4").is_none());
+
+    // the platform renamed in the config is still the same twitch
+    let renamed = PlatformEvent {
+        platform: "twitch_en".to_string(),
+        ..comment(1, "bobby", "renamed")
+    };
+    memory.record(&LinkedList::from([renamed]), 3000);
+    assert_eq!(memory.get("twitch", "1").unwrap().messages, 5);
+
+    // saved when dropped
+    drop(memory);
+    let memory = ViewerMemory::load(config).unwrap();
+    assert_eq!(memory.get("twitch", "1").unwrap().messages, 5);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,125 @@
+//! Prometheus metrics of the pipeline, served as `/metrics`.
+
+use std::sync::LazyLock;
+
+use prometheus::{
+    CounterVec, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, Opts, Registry,
+};
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Seconds, from a fast TTS sentence to a slow LLM reply.
+const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.0, 4.0, 8.0, 16.0, 32.0];
+
+pub struct Metrics {
+    registry: Registry,
+    /// Chat events from each platform.
+    pub comments: IntCounterVec,
+    /// Events dropped by the `max_comment` cap of `llm_loop`.
+    pub comments_dropped: IntCounterVec,
+    pub llm_first_token_seconds: Histogram,
+    pub llm_reply_seconds: Histogram,
+    pub tts_seconds: HistogramVec,
+    /// Seconds of speech synthesized, for the formats whose length is known.
+    pub tts_audio_seconds: CounterVec,
+    pub tts_failures: IntCounterVec,
+    pub downstream_failures: IntCounter,
+    /// Podcasts grouped by `PodcastStore`, not sent yet.
+    pub podcast_queue: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("llm_streaming".to_string()), None).unwrap();
+        let latency = |name: &str, help: &str| {
+            HistogramOpts::new(name, help).buckets(LATENCY_BUCKETS.to_vec())
+        };
+
+        let metrics = Self {
+            comments: IntCounterVec::new(
+                Opts::new("comments_total", "chat events received"),
+                &["platform"],
+            )
+            .unwrap(),
+            comments_dropped: IntCounterVec::new(
+                Opts::new(
+                    "comments_dropped_total",
+                    "chat events dropped by max_comment",
+                ),
+                &["platform"],
+            )
+            .unwrap(),
+            llm_first_token_seconds: Histogram::with_opts(latency(
+                "llm_first_token_seconds",
+                "time to the first chunk of a reply",
+            ))
+            .unwrap(),
+            llm_reply_seconds: Histogram::with_opts(latency(
+                "llm_reply_seconds",
+                "time to the last chunk of a reply",
+            ))
+            .unwrap(),
+            tts_seconds: HistogramVec::new(
+                latency("tts_seconds", "synthesis time of a sentence"),
+                &["provider"],
+            )
+            .unwrap(),
+            tts_audio_seconds: CounterVec::new(
+                Opts::new("tts_audio_seconds_total", "seconds of speech synthesized"),
+                &["provider"],
+            )
+            .unwrap(),
+            tts_failures: IntCounterVec::new(
+                Opts::new("tts_failures_total", "failed syntheses"),
+                &["provider"],
+            )
+            .unwrap(),
+            downstream_failures: IntCounter::new(
+                "downstream_failures_total",
+                "segments the downstream didn't accept",
+            )
+            .unwrap(),
+            podcast_queue: IntGauge::new("podcast_queue", "podcasts waiting to be sent").unwrap(),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 9] = [
+            Box::new(metrics.comments.clone()),
+            Box::new(metrics.comments_dropped.clone()),
+            Box::new(metrics.llm_first_token_seconds.clone()),
+            Box::new(metrics.llm_reply_seconds.clone()),
+            Box::new(metrics.tts_seconds.clone()),
+            Box::new(metrics.tts_audio_seconds.clone()),
+            Box::new(metrics.tts_failures.clone()),
+            Box::new(metrics.downstream_failures.clone()),
+            Box::new(metrics.podcast_queue.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+        metrics
+    }
+
+    /// The text exposition format.
+    pub fn encode(&self) -> anyhow::Result<String> {
+        let mut buf = vec![];
+        prometheus::TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
+
+#[test]
+fn test_metrics() {
+    METRICS.comments.with_label_values(&["twitch"]).inc();
+    METRICS.llm_first_token_seconds.observe(0.3);
+    METRICS
+        .tts_audio_seconds
+        .with_label_values(&["fake"])
+        .inc_by(1.5);
+
+    let text = METRICS.encode().unwrap();
+    assert!(text.contains("llm_streaming_comments_total{platform=\"twitch\"}"));
+    assert!(text.contains("llm_streaming_llm_first_token_seconds_bucket{le=\"0.5\"}"));
+    assert!(text.contains("llm_streaming_tts_audio_seconds_total{provider=\"fake\"}"));
+}
@@ -0,0 +1,238 @@
+//! In-process stand-ins for the LLM, TTS, ASR and downstream services, for tests.
+//!
+//! Everything is served by one axum app on an ephemeral port:
+//! - `POST /v1/chat/completions` streams the scripted replies as OpenAI style SSE
+//! - `POST /tts` the stable TTS, `POST /v1/tts` the fish TTS, both return silent wav
+//! - `POST /v1/audio/transcriptions` whisper style ASR
+//! - `POST /api/update_title`, `/api/say_form` and `/api/stop` the downstream
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::{Multipart, State},
+    http::{HeaderMap, StatusCode},
+    routing::post,
+    Json,
+};
+use bytes::Bytes;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub id: u64,
+    pub vtb_name: String,
+    pub text: Option<String>,
+    pub voice: Option<Bytes>,
+}
+
+#[derive(Default)]
+pub struct MockState {
+    /// Scripted LLM replies, `ok.` once they run out.
+    pub replies: Mutex<VecDeque<String>>,
+    /// Bodies of the chat completion requests.
+    pub requests: Mutex<Vec<serde_json::Value>>,
+    /// Speaker and text of every synthesis.
+    pub speech: Mutex<Vec<(String, String)>>,
+    pub transcript: Mutex<String>,
+    pub titles: Mutex<Vec<String>>,
+    pub segments: Mutex<Vec<Segment>>,
+    pub stops: AtomicUsize,
+}
+
+pub struct MockServer {
+    /// `http://127.0.0.1:<port>`
+    pub url: String,
+    pub state: Arc<MockState>,
+}
+
+impl MockServer {
+    pub async fn start() -> Self {
+        let state = Arc::new(MockState::default());
+        let app = axum::Router::new()
+            .route("/v1/chat/completions", post(chat))
+            .route("/tts", post(stable_tts))
+            .route("/v1/tts", post(fish_tts))
+            .route("/v1/audio/transcriptions", post(transcribe))
+            .route("/api/update_title", post(update_title))
+            .route("/api/say_form", post(say_form))
+            .route("/api/stop", post(stop))
+            .with_state(state.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Self {
+            url: format!("http://{addr}"),
+            state,
+        }
+    }
+
+    pub fn reply(&self, text: &str) {
+        self.state
+            .replies
+            .lock()
+            .unwrap()
+            .push_back(text.to_string());
+    }
+
+    pub fn downstream(&self) -> crate::config::DownstreamConfig {
+        crate::config::DownstreamConfig {
+            update_title_url: format!("{}/api/update_title", self.url),
+            segment_url: format!("{}/api/say_form", self.url),
+            playback_timeout_secs: 0,
+            stop_url: Some(format!("{}/api/stop", self.url)),
+        }
+    }
+
+    /// Waits until `n` segments arrived, panics after 10 seconds.
+    pub async fn wait_segments(&self, n: usize) -> Vec<Segment> {
+        let wait = async {
+            loop {
+                let segments = self.state.segments.lock().unwrap().clone();
+                if segments.len() >= n {
+                    return segments;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), wait)
+            .await
+            .unwrap_or_else(|_| panic!("timeout waiting for {n} segments"))
+    }
+}
+
+/// Streams the next reply a few characters per event, in network reads that
+/// don't line up with the events.
+async fn chat(
+    State(state): State<Arc<MockState>>,
+    Json(body): Json<serde_json::Value>,
+) -> impl axum::response::IntoResponse {
+    state.requests.lock().unwrap().push(body);
+    let reply = state
+        .replies
+        .lock()
+        .unwrap()
+        .pop_front()
+        .unwrap_or("ok.".to_string());
+
+    let chars: Vec<char> = reply.chars().collect();
+    let mut sse = String::new();
+    for piece in chars.chunks(3) {
+        let data = serde_json::json!({
+            "choices": [{"delta": {"content": piece.iter().collect::<String>()}, "finish_reason": null}]
+        });
+        sse.push_str(&format!("data: {}\n\n", data));
+    }
+    sse.push_str("data: [DONE]\n\n");
+
+    let reads: Vec<Result<Bytes, std::convert::Infallible>> = sse
+        .as_bytes()
+        .chunks(16)
+        .map(|b| Ok(Bytes::copy_from_slice(b)))
+        .collect();
+    (
+        [("content-type", "text/event-stream")],
+        axum::body::Body::from_stream(futures_util::stream::iter(reads)),
+    )
+}
+
+async fn stable_tts(
+    State(state): State<Arc<MockState>>,
+    Json(body): Json<serde_json::Value>,
+) -> Bytes {
+    let speaker = body["speaker"].as_str().unwrap_or_default().to_string();
+    let text = body["input"].as_str().unwrap_or_default().to_string();
+    state.speech.lock().unwrap().push((speaker, text));
+    crate::tts::silence_wav(0.1)
+}
+
+async fn fish_tts(
+    State(state): State<Arc<MockState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Bytes, StatusCode> {
+    let authorized = headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("Bearer "));
+    if !authorized {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let body: serde_json::Value =
+        rmp_serde::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let speaker = body["reference_id"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    let text = body["text"].as_str().unwrap_or_default().to_string();
+    state.speech.lock().unwrap().push((speaker, text));
+    Ok(crate::tts::silence_wav(0.1))
+}
+
+async fn transcribe(
+    State(state): State<Arc<MockState>>,
+    mut multipart: Multipart,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let mut audio = false;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?
+    {
+        audio |= field.name() == Some("file");
+    }
+    if !audio {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let text = state.transcript.lock().unwrap().clone();
+    Ok(Json(serde_json::json!({ "text": text })))
+}
+
+async fn update_title(
+    State(state): State<Arc<MockState>>,
+    Json(body): Json<serde_json::Value>,
+) -> &'static str {
+    let title = body["title"].as_str().unwrap_or_default().to_string();
+    state.titles.lock().unwrap().push(title);
+    "ok"
+}
+
+async fn say_form(
+    State(state): State<Arc<MockState>>,
+    mut multipart: Multipart,
+) -> Result<&'static str, StatusCode> {
+    let mut segment = Segment {
+        id: 0,
+        vtb_name: String::new(),
+        text: None,
+        voice: None,
+    };
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?
+    {
+        let name = field.name().unwrap_or_default().to_string();
+        let value = field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+        let text = || String::from_utf8_lossy(&value).to_string();
+        match name.as_str() {
+            "id" => segment.id = text().parse().map_err(|_| StatusCode::BAD_REQUEST)?,
+            "vtb_name" => segment.vtb_name = text(),
+            "text" => segment.text = Some(text()),
+            "voice" => segment.voice = Some(value.clone()),
+            _ => {}
+        }
+    }
+    state.segments.lock().unwrap().push(segment);
+    Ok("ok")
+}
+
+async fn stop(State(state): State<Arc<MockState>>) -> &'static str {
+    state.stops.fetch_add(1, Ordering::SeqCst);
+    "ok"
+}
@@ -0,0 +1,158 @@
+//! Append-only JSONL transcript of a session, one `Entry` per line.
+
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+use crate::{config::RecorderConfig, stream_platform::PlatformEvent};
+
+static RECORDER: OnceLock<Recorder> = OnceLock::new();
+static NEXT_REQUEST: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    /// A chat event as `llm_loop` received it.
+    Comment {
+        event: PlatformEvent,
+    },
+    /// The body posted by `llm_stable`, `request` ties the chunks to it.
+    Prompt {
+        request: u64,
+        url: String,
+        body: serde_json::Value,
+    },
+    Chunk {
+        request: u64,
+        text: String,
+    },
+    Tts {
+        provider: String,
+        text: String,
+        latency_ms: u64,
+        audio_secs: Option<f64>,
+        error: Option<String>,
+    },
+    /// A segment posted to the downstream.
+    Delivery {
+        id: u64,
+        text: Option<String>,
+        error: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+    /// unix milliseconds
+    pub at_ms: u64,
+    #[serde(flatten)]
+    pub record: Record,
+}
+
+pub struct Recorder {
+    file: Mutex<std::fs::File>,
+}
+
+impl Recorder {
+    pub fn open(config: &RecorderConfig) -> anyhow::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn write(&self, record: Record) -> anyhow::Result<()> {
+        let at_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let mut line = serde_json::to_vec(&Entry { at_ms, record })?;
+        line.push(b'\n');
+        // a single write per line, so lines never interleave
+        self.file.lock().unwrap().write_all(&line)?;
+        Ok(())
+    }
+}
+
+/// All entries of a recording.
+pub fn read(path: &str) -> anyhow::Result<Vec<Entry>> {
+    let text = std::fs::read_to_string(path)?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .map(|(i, l)| {
+            serde_json::from_str(l).map_err(|e| anyhow::anyhow!("{}:{}: {}", path, i + 1, e))
+        })
+        .collect()
+}
+
+/// Records everything from now on into the file of `config`.
+pub fn init(config: &RecorderConfig) -> anyhow::Result<()> {
+    RECORDER
+        .set(Recorder::open(config)?)
+        .map_err(|_| anyhow::anyhow!("recorder already initialized"))
+}
+
+/// Does nothing unless `init` was called.
+pub fn record(record: Record) {
+    if let Some(recorder) = RECORDER.get() {
+        if let Err(e) = recorder.write(record) {
+            log::error!("record failed: {:?}", e);
+        }
+    }
+}
+
+/// Id of the next LLM request.
+pub fn next_request() -> u64 {
+    NEXT_REQUEST.fetch_add(1, Ordering::Relaxed)
+}
+
+#[test]
+fn test_recorder() {
+    use crate::stream_platform::{SteamEvent, User};
+
+    let path = std::env::temp_dir().join(format!("session_{}.jsonl", std::process::id()));
+    let config = RecorderConfig {
+        path: path.to_string_lossy().to_string(),
+    };
+    let records = vec![
+        Record::Comment {
+            event: PlatformEvent {
+                platform: "twitch".to_string(),
+                source: "twitch".to_string(),
+                event: SteamEvent::Comment {
+                    user: User::new(1, "bob"),
+                    content: "hi".to_string(),
+                },
+            },
+        },
+        Record::Chunk {
+            request: 0,
+            text: "hello".to_string(),
+        },
+    ];
+
+    // appends across runs
+    for record in &records {
+        Recorder::open(&config)
+            .unwrap()
+            .write(record.clone())
+            .unwrap();
+    }
+
+    let lines = std::fs::read_to_string(&path).unwrap();
+    let entries = read(&config.path).unwrap();
+    assert_eq!(
+        entries.into_iter().map(|e| e.record).collect::<Vec<_>>(),
+        records
+    );
+    assert!(lines.starts_with(r#"{"at_ms":"#) && lines.contains(r#""type":"chunk""#));
+
+    std::fs::remove_file(path).unwrap();
+}
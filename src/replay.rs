@@ -0,0 +1,352 @@
+//! `llm_streaming replay`: runs the chat of a recorded session through the
+//! pipeline again, with the recorded LLM replies and speech or the live ones.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+
+use crate::{
+    config::{RecorderConfig, ReplayConfig, ReplaySource, TTSConfig},
+    recorder::{Entry, Record},
+    status::Status,
+    stream_platform::{multi::MultiPlatform, replay::ReplayPlatform, SteamEvent},
+    tts::{Tts, TtsEngine},
+};
+
+pub struct Replay {
+    config: ReplayConfig,
+    /// Chat events by platform and source, with their offsets from the start
+    /// of the recording.
+    chat: BTreeMap<(String, String), VecDeque<(Duration, SteamEvent)>>,
+    /// Text to the recorded length of the speech or the error.
+    speech: HashMap<String, VecDeque<Result<f64, String>>>,
+    /// Messages and chunks of every recorded LLM request, in order.
+    replies: Vec<(serde_json::Value, Vec<String>)>,
+    remaining: Arc<tokio::sync::watch::Sender<usize>>,
+}
+
+impl Replay {
+    pub fn load(config: ReplayConfig) -> anyhow::Result<Self> {
+        let entries = crate::recorder::read(&config.path)?;
+        Ok(Self::from_entries(config, entries))
+    }
+
+    fn from_entries(config: ReplayConfig, entries: Vec<Entry>) -> Self {
+        let first_at = entries.iter().map(|e| e.at_ms).min().unwrap_or_default();
+        let mut chat: BTreeMap<(String, String), VecDeque<_>> = BTreeMap::new();
+        let mut speech: HashMap<String, VecDeque<_>> = HashMap::new();
+        let mut replies = vec![];
+        let mut requests = HashMap::new();
+
+        for Entry { at_ms, record } in entries {
+            match record {
+                Record::Comment { event } => {
+                    let offset = Duration::from_millis(at_ms - first_at);
+                    chat.entry((event.platform, event.source))
+                        .or_default()
+                        .push_back((offset, event.event));
+                }
+                Record::Prompt { request, body, .. } => {
+                    requests.insert(request, replies.len());
+                    replies.push((body["messages"].clone(), vec![]));
+                }
+                Record::Chunk { request, text } => {
+                    if let Some(i) = requests.get(&request) {
+                        replies[*i].1.push(text);
+                    }
+                }
+                Record::Tts {
+                    text,
+                    audio_secs,
+                    error,
+                    ..
+                } => {
+                    let r = match error {
+                        Some(e) => Err(e),
+                        None => Ok(audio_secs.unwrap_or_default()),
+                    };
+                    speech.entry(text).or_default().push_back(r);
+                }
+                Record::Delivery { .. } => {}
+            }
+        }
+
+        log::info!(
+            "replay {} chat events, {} llm replies",
+            chat.values().map(|c| c.len()).sum::<usize>(),
+            replies.len()
+        );
+        Self {
+            config,
+            chat,
+            speech,
+            replies,
+            remaining: Arc::new(tokio::sync::watch::channel(0).0),
+        }
+    }
+
+    /// Fails if `recorder` would append to the recording being replayed.
+    pub fn check_recorder(&self, recorder: &RecorderConfig) -> anyhow::Result<()> {
+        let replayed = std::fs::canonicalize(&self.config.path)?;
+        if std::fs::canonicalize(&recorder.path).is_ok_and(|p| p == replayed) {
+            return Err(anyhow::anyhow!(
+                "[recorder] path {} is the recording being replayed",
+                recorder.path
+            ));
+        }
+        Ok(())
+    }
+
+    /// One platform for each in the recording, replaying from now on.
+    pub fn platforms(&self, status: Arc<Status>) -> MultiPlatform {
+        let start = tokio::time::Instant::now();
+        self.remaining.send_replace(self.chat.len());
+        let mut platforms = MultiPlatform::with_status(status);
+        for ((name, source), events) in &self.chat {
+            let platform = ReplayPlatform::new(
+                events.clone(),
+                start,
+                self.config.speed,
+                self.remaining.clone(),
+            );
+            // recorded before there was a source
+            let source = if source.is_empty() { name } else { source };
+            platforms.push_kind(name, source, platform);
+        }
+        platforms
+    }
+
+    /// Serves the recorded replies on a local chat completion endpoint, returns its url.
+    /// `None` if the LLM is live.
+    pub async fn serve_llm(&self) -> anyhow::Result<Option<String>> {
+        if self.config.llm != ReplaySource::Recorded {
+            return Ok(None);
+        }
+
+        let replies = Arc::new(Mutex::new(
+            self.replies.iter().cloned().map(Some).collect::<Vec<_>>(),
+        ));
+        let app = axum::Router::new().route(
+            "/v1/chat/completions",
+            axum::routing::post(
+                move |axum::Json(body): axum::Json<serde_json::Value>| async move {
+                    let chunks = recorded_reply(&mut replies.lock().unwrap(), &body["messages"]);
+                    let mut sse = String::new();
+                    for chunk in chunks {
+                        let data = serde_json::json!({
+                            "choices": [{"delta": {"content": chunk}, "finish_reason": null}]
+                        });
+                        sse.push_str(&format!("data: {}\n\n", data));
+                    }
+                    sse.push_str("data: [DONE]\n\n");
+                    ([("content-type", "text/event-stream")], sse)
+                },
+            ),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Ok(Some(format!("http://{addr}/v1/chat/completions")))
+    }
+
+    /// Speaks silence as long as the recorded speech. `None` if the TTS is live.
+    pub fn tts(&self, config: &TTSConfig) -> Option<Tts> {
+        if self.config.tts != ReplaySource::Recorded {
+            return None;
+        }
+        Some(Tts {
+            engine: Arc::new(RecordedTts {
+                speech: Mutex::new(self.speech.clone()),
+            }),
+            voice: config.speaker().to_string(),
+            vtb_name: config.vtb_name().to_string(),
+        })
+    }
+
+    /// Resolves once the chat was replayed and the agent answered all of it.
+    pub async fn finished(&self, status: &Status) {
+        let mut remaining = self.remaining.subscribe();
+        let _ = remaining.wait_for(|n| *n == 0).await;
+
+        // comments are handed over before the reply starts, so check twice
+        let mut idle = 0;
+        while idle < 2 {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            let report = status.report();
+            if report.pending_comments == 0 && report.reply.is_none() {
+                idle += 1;
+            } else {
+                idle = 0;
+            }
+        }
+    }
+}
+
+/// The first unused reply to the same messages, otherwise the next one in order.
+fn recorded_reply(
+    replies: &mut [Option<(serde_json::Value, Vec<String>)>],
+    messages: &serde_json::Value,
+) -> Vec<String> {
+    let i = replies
+        .iter()
+        .position(|r| r.as_ref().is_some_and(|(m, _)| m == messages))
+        .or_else(|| {
+            let i = replies.iter().position(|r| r.is_some());
+            log::warn!("no recorded reply to the same prompt, use {:?}", i);
+            i
+        });
+    i.and_then(|i| replies[i].take())
+        .map(|(_, chunks)| chunks)
+        .unwrap_or_default()
+}
+
+struct RecordedTts {
+    speech: Mutex<HashMap<String, VecDeque<Result<f64, String>>>>,
+}
+
+impl TtsEngine for RecordedTts {
+    fn name(&self) -> &str {
+        "recorded"
+    }
+
+    fn synthesize<'a>(
+        &'a self,
+        text: &'a str,
+        _voice: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Bytes>> {
+        let recorded = self
+            .speech
+            .lock()
+            .unwrap()
+            .get_mut(text)
+            .and_then(|s| s.pop_front());
+        Box::pin(async move {
+            match recorded {
+                Some(Ok(secs)) => Ok(crate::tts::silence_wav(secs)),
+                Some(Err(e)) => Err(anyhow::anyhow!("recorded tts error: {}", e)),
+                None => Err(anyhow::anyhow!("no recorded speech of {}", text)),
+            }
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_replay() {
+    use crate::{
+        llm::{llm::Content, llm_stable, ChatOptions},
+        stream_platform::{PlatformEvent, User},
+    };
+
+    let user = |message: &str| Content {
+        role: crate::llm::llm::Role::User,
+        message: message.to_string(),
+    };
+    let entry = |at_ms, record| Entry { at_ms, record };
+    let prompt = |request, message: &str| Record::Prompt {
+        request,
+        url: "http://llm".to_string(),
+        body: serde_json::json!({ "stream": true, "messages": [user(message)] }),
+    };
+    let chunk = |request, text: &str| Record::Chunk {
+        request,
+        text: text.to_string(),
+    };
+    let entries = vec![
+        entry(
+            1000,
+            Record::Comment {
+                event: PlatformEvent {
+                    platform: "twitch".to_string(),
+                    source: "twitch".to_string(),
+                    event: SteamEvent::Comment {
+                        user: User::new(1, "bob"),
+                        content: "hi".to_string(),
+                    },
+                },
+            },
+        ),
+        entry(1100, prompt(7, "first")),
+        entry(1200, prompt(8, "second")),
+        entry(1300, chunk(8, "two.")),
+        entry(1400, chunk(7, "one.")),
+        entry(
+            1500,
+            Record::Tts {
+                provider: "stable".to_string(),
+                text: "one.".to_string(),
+                latency_ms: 30,
+                audio_secs: Some(0.5),
+                error: None,
+            },
+        ),
+    ];
+    let replay = Replay::from_entries(
+        ReplayConfig {
+            path: String::new(),
+            speed: 0.0,
+            llm: ReplaySource::Recorded,
+            tts: ReplaySource::Recorded,
+        },
+        entries,
+    );
+
+    let status = Arc::new(Status::default());
+    let mut platforms = replay.platforms(status.clone());
+    let event = platforms.next_event().await.unwrap();
+    assert_eq!(event.platform, "twitch");
+    assert_eq!(
+        event.event,
+        SteamEvent::Comment {
+            user: User::new(1, "bob"),
+            content: "hi".to_string(),
+        }
+    );
+    replay.finished(&status).await;
+
+    // matched by the prompt, not the order
+    let url = replay.serve_llm().await.unwrap().unwrap();
+    let mut reply = llm_stable(&url, "", None, &ChatOptions::default(), [user("second")])
+        .await
+        .unwrap();
+    assert_eq!(reply.next_chunk().await.unwrap().unwrap(), "two.");
+    assert_eq!(reply.next_chunk().await.unwrap(), None);
+
+    let tts = replay.tts(&TTSConfig::Stable(crate::config::StableTTS {
+        base_url: String::new(),
+        speaker: "kelly".to_string(),
+        vtb_name: "vtb".to_string(),
+    }));
+    let tts = tts.unwrap();
+    let audio = tts.synthesize("one.").await.unwrap();
+    assert_eq!(
+        crate::tts::wav_duration(&audio),
+        Some(Duration::from_millis(500))
+    );
+    assert!(tts.synthesize("one.").await.is_err());
+
+    // never appends to the recording it replays
+    let path = std::env::temp_dir().join(format!("replay_{}.jsonl", std::process::id()));
+    std::fs::write(&path, "").unwrap();
+    let replay = Replay::from_entries(
+        ReplayConfig {
+            path: path.to_string_lossy().to_string(),
+            speed: 0.0,
+            llm: ReplaySource::Recorded,
+            tts: ReplaySource::Recorded,
+        },
+        vec![],
+    );
+    let recorder = |path: String| RecorderConfig { path };
+    assert!(replay
+        .check_recorder(&recorder(path.to_string_lossy().to_string()))
+        .is_err());
+    assert!(replay
+        .check_recorder(&recorder(format!("{}.new", path.to_string_lossy())))
+        .is_ok());
+    std::fs::remove_file(path).unwrap();
+}
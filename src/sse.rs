@@ -0,0 +1,173 @@
+//! Incremental Server-Sent Events decoder.
+//!
+//! https://html.spec.whatwg.org/multipage/server-sent-events.html#event-stream-interpretation
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SseEvent {
+    /// `message` unless set by an `event:` field.
+    pub event: String,
+    pub data: String,
+    /// Last event id seen on the stream.
+    pub id: Option<String>,
+}
+
+/// Feed it raw body chunks, split anywhere, and get complete events back.
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    line: Vec<u8>,
+    /// The previous chunk ended with `\r`, a leading `\n` belongs to it.
+    skip_lf: bool,
+    started: bool,
+    event: String,
+    data: String,
+    has_data: bool,
+    id: Option<String>,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<SseEvent> {
+        let mut events = vec![];
+        for &b in bytes {
+            if self.skip_lf {
+                self.skip_lf = false;
+                if b == b'\n' {
+                    continue;
+                }
+            }
+            match b {
+                b'\r' => {
+                    self.skip_lf = true;
+                    self.end_line(&mut events);
+                }
+                b'\n' => self.end_line(&mut events),
+                _ => self.line.push(b),
+            }
+        }
+        events
+    }
+
+    /// End of stream. A trailing event without the final blank line is
+    /// dispatched instead of discarded, some servers never send one.
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        let mut events = vec![];
+        if !self.line.is_empty() {
+            self.end_line(&mut events);
+        }
+        self.end_line(&mut events);
+        events.pop()
+    }
+
+    fn end_line(&mut self, events: &mut Vec<SseEvent>) {
+        let mut line = std::mem::take(&mut self.line);
+        if !self.started {
+            self.started = true;
+            if line.starts_with("\u{feff}".as_bytes()) {
+                line.drain(..3);
+            }
+        }
+        let line = String::from_utf8_lossy(&line);
+
+        if line.is_empty() {
+            if let Some(event) = self.dispatch() {
+                events.push(event);
+            }
+            return;
+        }
+        if line.starts_with(':') {
+            // comment, e.g. keep-alive
+            return;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line.as_ref(), ""),
+        };
+        match field {
+            "event" => self.event = value.to_string(),
+            "data" => {
+                if self.has_data {
+                    self.data.push('\n');
+                }
+                self.data.push_str(value);
+                self.has_data = true;
+            }
+            "id" if !value.contains('\0') => self.id = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = std::mem::take(&mut self.event);
+        if !std::mem::take(&mut self.has_data) {
+            return None;
+        }
+        Some(SseEvent {
+            event: if event.is_empty() {
+                "message".to_string()
+            } else {
+                event
+            },
+            data: std::mem::take(&mut self.data),
+            id: self.id.clone(),
+        })
+    }
+}
+
+#[test]
+fn test_split_at_every_offset() {
+    let stream = "\u{feff}: keep-alive\r\n\
+        data: {\"a\":\"你好\"}\r\n\r\n\
+        event: error\n\
+        id: 7\n\
+        data: line one\n\
+        data:line two\n\n\
+        data\r\r\
+        retry: 1000\n\
+        event: ignored-without-data\n\n\
+        data: [DONE]\n\n";
+
+    let message = |data: &str, id: Option<&str>| SseEvent {
+        event: "message".to_string(),
+        data: data.to_string(),
+        id: id.map(str::to_string),
+    };
+    let expect = vec![
+        message("{\"a\":\"你好\"}", None),
+        SseEvent {
+            event: "error".to_string(),
+            data: "line one\nline two".to_string(),
+            id: Some("7".to_string()),
+        },
+        message("", Some("7")),
+        message("[DONE]", Some("7")),
+    ];
+
+    let bytes = stream.as_bytes();
+    for i in 0..=bytes.len() {
+        let mut decoder = SseDecoder::new();
+        let mut events = decoder.push(&bytes[..i]);
+        events.extend(decoder.push(&bytes[i..]));
+        events.extend(decoder.finish());
+        assert_eq!(events, expect, "split at {i}");
+    }
+
+    // one byte at a time
+    let mut decoder = SseDecoder::new();
+    let mut events = vec![];
+    for b in bytes {
+        events.extend(decoder.push(std::slice::from_ref(b)));
+    }
+    assert_eq!(events, expect);
+}
+
+#[test]
+fn test_finish_without_blank_line() {
+    let mut decoder = SseDecoder::new();
+    assert!(decoder.push(b"data: a\n\ndata: b").len() == 1);
+    assert_eq!(decoder.finish().unwrap().data, "b");
+    assert_eq!(decoder.finish(), None);
+}
@@ -0,0 +1,153 @@
+//! Snapshots of the conversation, so a restart mid-stream resumes where it stopped.
+
+use std::{
+    collections::LinkedList,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{config::StateConfig, llm::llm::Content, stream_platform::PlatformEvent};
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Snapshot {
+    pub session: String,
+    /// unix seconds
+    pub saved_at: u64,
+    pub dynamic_prompts: LinkedList<Content>,
+    /// The rolling summary of the stream.
+    pub summary: String,
+    pub title_index: usize,
+    /// Comments not handed to the agent yet.
+    pub pending_comments: Vec<PlatformEvent>,
+}
+
+/// The latest state of every part, written to disk on a timer and at shutdown.
+pub struct StateStore {
+    config: StateConfig,
+    /// Restored from disk or updated since the start, so there's something to resume.
+    resumable: AtomicBool,
+    snapshot: Mutex<Snapshot>,
+}
+
+impl StateStore {
+    /// Restores the snapshot on disk if it belongs to the configured session.
+    pub fn load(config: StateConfig) -> anyhow::Result<Self> {
+        let restored = match std::fs::read(&config.path) {
+            Ok(data) => {
+                let snapshot: Snapshot = serde_json::from_slice(&data)?;
+                if snapshot.session == config.session {
+                    log::info!(
+                        "resume session {} saved at {}",
+                        snapshot.session,
+                        snapshot.saved_at
+                    );
+                    Some(snapshot)
+                } else {
+                    log::info!(
+                        "new session {}, ignore the snapshot of {}",
+                        config.session,
+                        snapshot.session
+                    );
+                    None
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        let resumable = AtomicBool::new(restored.is_some());
+        let snapshot = restored.unwrap_or_else(|| Snapshot {
+            session: config.session.clone(),
+            ..Default::default()
+        });
+        Ok(Self {
+            config,
+            resumable,
+            snapshot: Mutex::new(snapshot),
+        })
+    }
+
+    /// Where to pick up, either the previous run of this session or a task
+    /// restarted by the supervisor.
+    pub fn resume(&self) -> Option<Snapshot> {
+        if self.resumable.load(Ordering::Relaxed) {
+            Some(self.snapshot.lock().unwrap().clone())
+        } else {
+            None
+        }
+    }
+
+    pub fn update<F: FnOnce(&mut Snapshot)>(&self, f: F) {
+        f(&mut self.snapshot.lock().unwrap());
+        self.resumable.store(true, Ordering::Relaxed);
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let data = {
+            let mut snapshot = self.snapshot.lock().unwrap();
+            snapshot.saved_at = crate::util::now();
+            serde_json::to_vec_pretty(&*snapshot)?
+        };
+        let tmp = format!("{}.tmp", self.config.path);
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(tmp, &self.config.path)?;
+        Ok(())
+    }
+
+    pub async fn run_timer(self: Arc<Self>) {
+        let period = Duration::from_secs(self.config.interval_secs.max(1));
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.save() {
+                log::error!("save state failed: {:?}", e);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_state_store() {
+    use crate::stream_platform::{SteamEvent, User};
+
+    let path = std::env::temp_dir().join(format!("state_{}.json", std::process::id()));
+    let config = |session: &str| StateConfig {
+        path: path.to_string_lossy().to_string(),
+        session: session.to_string(),
+        interval_secs: 30,
+    };
+
+    let store = StateStore::load(config("stream-1")).unwrap();
+    assert!(store.resume().is_none());
+    store.update(|s| {
+        s.dynamic_prompts.push_back(Content {
+            role: crate::llm::llm::Role::User,
+            message: "hi".to_string(),
+        });
+        s.title_index = 3;
+        s.pending_comments.push(PlatformEvent {
+            platform: "twitch".to_string(),
+            source: "twitch".to_string(),
+            event: SteamEvent::Comment {
+                user: User::new(1, "bob"),
+                content: "hello".to_string(),
+            },
+        });
+    });
+    store.save().unwrap();
+    let saved = store.snapshot.lock().unwrap().clone();
+    assert_eq!(store.resume(), Some(saved.clone()));
+
+    let store = StateStore::load(config("stream-1")).unwrap();
+    assert_eq!(store.resume(), Some(saved));
+
+    // another session starts from scratch
+    let store = StateStore::load(config("stream-2")).unwrap();
+    assert!(store.resume().is_none());
+    assert_eq!(store.snapshot.lock().unwrap().session, "stream-2");
+
+    std::fs::remove_file(path).unwrap();
+}
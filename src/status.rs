@@ -0,0 +1,123 @@
+//! What the bot is doing right now, served as `/status`.
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::supervisor::{TaskHealth, TaskState};
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub enum PlatformState {
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct ReplyProgress {
+    /// unix seconds
+    pub started_at: u64,
+    pub segments_delivered: usize,
+    /// Text of the delivered segments.
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct LastError {
+    /// unix seconds
+    pub at: u64,
+    pub source: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct Report {
+    pub platforms: BTreeMap<String, PlatformState>,
+    /// Comments waiting in `llm_loop` for the agent.
+    pub pending_comments: usize,
+    /// Podcasts grouped by `PodcastStore`, not sent yet.
+    pub queued_podcasts: usize,
+    pub reply: Option<ReplyProgress>,
+    /// Time to the first chunk of the last LLM reply.
+    pub llm_latency_ms: Option<u64>,
+    /// Last synthesis of a sentence.
+    pub tts_latency_ms: Option<u64>,
+    pub last_error: Option<LastError>,
+    pub tasks: BTreeMap<String, TaskHealth>,
+    pub shutting_down: bool,
+}
+
+impl Report {
+    /// Up and chatting: no task is down, a platform is connected and no shutdown started.
+    pub fn is_ready(&self) -> bool {
+        !self.shutting_down
+            && self.tasks.values().all(|t| t.state == TaskState::Running)
+            && self
+                .platforms
+                .values()
+                .any(|p| *p == PlatformState::Connected)
+    }
+
+    /// Alive unless a task was given up on.
+    pub fn is_healthy(&self) -> bool {
+        self.tasks.values().all(|t| t.state != TaskState::Failed)
+    }
+}
+
+/// Shared by every part of the pipeline, each one updates its own fields.
+#[derive(Debug, Default)]
+pub struct Status {
+    report: Mutex<Report>,
+}
+
+impl Status {
+    pub fn update<F: FnOnce(&mut Report)>(&self, f: F) {
+        f(&mut self.report.lock().unwrap());
+    }
+
+    pub fn error(&self, source: &str, e: &anyhow::Error) {
+        self.update(|s| {
+            s.last_error = Some(LastError {
+                at: crate::util::now(),
+                source: source.to_string(),
+                message: format!("{:#}", e),
+            })
+        });
+    }
+
+    pub fn platform(&self, name: &str, state: PlatformState) {
+        self.update(|s| {
+            s.platforms.insert(name.to_string(), state);
+        });
+    }
+
+    pub fn report(&self) -> Report {
+        self.report.lock().unwrap().clone()
+    }
+}
+
+#[test]
+fn test_readiness() {
+    let mut report = Report::default();
+    assert!(!report.is_ready() && report.is_healthy());
+
+    report
+        .platforms
+        .insert("twitch".to_string(), PlatformState::Connected);
+    report
+        .platforms
+        .insert("bilibili".to_string(), PlatformState::Disconnected);
+    let mut task = TaskHealth {
+        state: TaskState::Running,
+        restarts: 0,
+        last_error: None,
+    };
+    report.tasks.insert("llm_loop".to_string(), task.clone());
+    assert!(report.is_ready());
+
+    task.state = TaskState::Restarting;
+    report.tasks.insert("llm_loop".to_string(), task.clone());
+    assert!(!report.is_ready() && report.is_healthy());
+
+    task.state = TaskState::Failed;
+    report.tasks.insert("llm_loop".to_string(), task);
+    assert!(!report.is_healthy());
+}
@@ -34,19 +34,78 @@ impl BiliLiveClient {
     }
 }
 
+/// 1总督 2提督 3舰长, price in CNY per month.
+fn guard_level(level: u32) -> (&'static str, f64) {
+    match level {
+        1 => ("总督", 19998.0),
+        2 => ("提督", 1998.0),
+        _ => ("舰长", 198.0),
+    }
+}
+
+/// bilibili gold coin, 1000 coins = 1 CNY. Silver coins are free.
+fn coin_to_cny(coin: u32, coin_type: &str) -> f64 {
+    if coin_type == "silver" {
+        0.0
+    } else {
+        coin as f64 / 1000.0
+    }
+}
+
+fn to_steam_event(msg: bilili_rs::live_ws::NotificationMsg) -> Option<super::SteamEvent> {
+    use super::{SteamEvent, User};
+    use bilili_rs::live_ws::NotificationMsg;
+
+    match msg {
+        NotificationMsg::DANMU_MSG { info } => Some(SteamEvent::Comment {
+            user: User::new(info.uid, info.uname),
+            content: info.text,
+        }),
+        NotificationMsg::SEND_GIFT { data } => Some(SteamEvent::Gift {
+            user: User::new(data.uid, data.uname),
+            gift: data.gift_name,
+            count: data.num,
+            amount: coin_to_cny(data.total_coin, &data.coin_type),
+            currency: "CNY".to_string(),
+        }),
+        // sums up SEND_GIFTs that already arrived one by one
+        NotificationMsg::COMBO_SEND { .. } => None,
+        NotificationMsg::GUARD_BUY { data } => {
+            let (level, price) = guard_level(data.guard_level);
+            Some(SteamEvent::Membership {
+                user: User::new(data.uid, data.username),
+                level: level.to_string(),
+                months: data.num,
+                amount: price * data.num as f64,
+                currency: "CNY".to_string(),
+            })
+        }
+        NotificationMsg::SUPER_CHAT_MESSAGE { data } => Some(SteamEvent::SuperChat {
+            user: User::new(data.uid, data.user_info.uname),
+            content: data.message,
+            amount: data.price as f64,
+            currency: "CNY".to_string(),
+        }),
+        NotificationMsg::INTERACT_WORD { data } => match data.msg_type {
+            1 => Some(SteamEvent::Enter {
+                user: User::new(data.uid, data.uname),
+            }),
+            2 | 5 => Some(SteamEvent::Follow {
+                user: User::new(data.uid, data.uname),
+            }),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 impl super::StreamPlatform for BiliLiveClient {
     async fn next_event(&mut self) -> anyhow::Result<super::SteamEvent> {
         while let Some(s) = self.next_bili_event().await {
-            match s {
-                bilili_rs::live_ws::ServerLiveMessage::Notification(
-                    bilili_rs::live_ws::NotificationMsg::DANMU_MSG { info },
-                ) => {
-                    return Ok(super::SteamEvent::Comment {
-                        user: info.uname,
-                        content: info.text,
-                    });
+            if let bilili_rs::live_ws::ServerLiveMessage::Notification(msg) = s {
+                if let Some(event) = to_steam_event(msg) {
+                    return Ok(event);
                 }
-                _ => {}
             }
         }
         Err(anyhow::anyhow!("bilibili ws closed"))
@@ -59,3 +118,78 @@ impl BiliLiveClient {
         Ok(Self::new(client, config.room_id, config.max_retry))
     }
 }
+
+#[test]
+fn test_to_steam_event() {
+    use super::{SteamEvent, User};
+
+    let gift = serde_json::from_str(
+        r#"{"cmd":"SEND_GIFT","data":{"giftId":31036,"giftName":"小花花","total_coin":500,"num":5,"uid":1,"uname":"bob"}}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        to_steam_event(gift),
+        Some(SteamEvent::Gift {
+            user: User::new(1, "bob"),
+            gift: "小花花".to_string(),
+            count: 5,
+            amount: 0.5,
+            currency: "CNY".to_string(),
+        })
+    );
+
+    let combo = serde_json::from_str(
+        r#"{"cmd":"COMBO_SEND","data":{"gift_id":31036,"gift_name":"小花花","total_num":5,"combo_total_coin":500,"uid":1,"uname":"bob"}}"#,
+    )
+    .unwrap();
+    assert_eq!(to_steam_event(combo), None);
+
+    let silver = serde_json::from_str(
+        r#"{"cmd":"SEND_GIFT","data":{"giftId":1,"giftName":"辣条","total_coin":1000,"coin_type":"silver","num":10,"uid":1,"uname":"bob"}}"#,
+    )
+    .unwrap();
+    let silver = to_steam_event(silver).unwrap();
+    assert!(!silver.is_paid());
+
+    let guard = serde_json::from_str(
+        r#"{"cmd":"GUARD_BUY","data":{"gift_id":10003,"gift_name":"舰长","guard_level":3,"num":2,"uid":1,"username":"bob"}}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        to_steam_event(guard),
+        Some(SteamEvent::Membership {
+            user: User::new(1, "bob"),
+            level: "舰长".to_string(),
+            months: 2,
+            amount: 396.0,
+            currency: "CNY".to_string(),
+        })
+    );
+
+    let super_chat = serde_json::from_str(
+        r##"{"cmd":"SUPER_CHAT_MESSAGE","data":{"background_bottom_color":"#2A60B2","background_color":"#EDF5FF","background_color_end":"#405D85","background_color_start":"#3171B0","background_icon":"","background_image":"","background_price_color":"#7497CD","color_point":0.7,"dmscore":120,"end_time":1718000060,"gift":{"gift_id":12000,"gift_name":"醒目留言","num":1},"id":9876543,"is_ranked":1,"is_send_audit":0,"medal_info":{"anchor_roomid":21452505,"anchor_uname":"主播","guard_level":0,"icon_id":0,"is_lighted":1,"medal_color":"#1a544b","medal_color_border":12632256,"medal_color_end":12632256,"medal_color_start":12632256,"medal_level":5,"medal_name":"粉丝","special":"","target_id":2},"message":"主播晚上好，今天唱什么歌？","message_font_color":"#A3F6FF","message_trans":"","price":30,"rate":1000,"start_time":1718000000,"time":60,"token":"5C4E1A2B","trans_mark":0,"ts":1718000000,"uid":1,"user_info":{"face":"http://i0.hdslb.com/bfs/face/member/noface.jpg","face_frame":"","guard_level":0,"is_main_vip":0,"is_svip":0,"is_vip":0,"level_color":"#969696","manager":0,"name_color":"#666666","title":"0","uname":"bob","user_level":12}},"roomid":21452505}"##,
+    )
+    .unwrap();
+    let super_chat = to_steam_event(super_chat);
+    assert_eq!(
+        super_chat,
+        Some(SteamEvent::SuperChat {
+            user: User::new(1, "bob"),
+            content: "主播晚上好，今天唱什么歌？".to_string(),
+            amount: 30.0,
+            currency: "CNY".to_string(),
+        })
+    );
+    assert!(super_chat.unwrap().is_paid());
+
+    let follow = serde_json::from_str(
+        r#"{"cmd":"INTERACT_WORD","data":{"uid":1,"uname":"bob","msg_type":2}}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        to_steam_event(follow),
+        Some(SteamEvent::Follow {
+            user: User::new(1, "bob")
+        })
+    );
+}
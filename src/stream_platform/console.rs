@@ -0,0 +1,99 @@
+//! Chat typed as `user: message` lines on stdin or read from a text file, for development.
+
+use std::time::Duration;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+
+use super::{SteamEvent, User};
+
+/// `user: message`, full-width colons work too. Blank lines and `#` comments are skipped.
+fn parse_line(line: &str) -> Option<SteamEvent> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let Some((user, content)) = line.split_once([':', '：']) else {
+        log::warn!("skip line without `user: message`: {}", line);
+        return None;
+    };
+    let (user, content) = (user.trim(), content.trim());
+    if user.is_empty() || content.is_empty() {
+        return None;
+    }
+    Some(SteamEvent::Comment {
+        user: User::new(user, user),
+        content: content.to_string(),
+    })
+}
+
+pub struct ConsoleChat<R> {
+    lines: tokio::io::Lines<R>,
+    /// Wait before every line, so a file reads like a chat.
+    interval: Duration,
+}
+
+impl<R: AsyncBufRead + Unpin> ConsoleChat<R> {
+    pub fn new(reader: R, interval: Duration) -> Self {
+        Self {
+            lines: reader.lines(),
+            interval,
+        }
+    }
+}
+
+impl ConsoleChat<BufReader<tokio::io::Stdin>> {
+    pub fn stdin() -> Self {
+        Self::new(BufReader::new(tokio::io::stdin()), Duration::ZERO)
+    }
+}
+
+impl ConsoleChat<BufReader<tokio::fs::File>> {
+    pub async fn from_config(config: crate::config::ChatFileConfig) -> anyhow::Result<Self> {
+        let file = tokio::fs::File::open(&config.path).await?;
+        Ok(Self::new(
+            BufReader::new(file),
+            Duration::from_millis(config.interval_ms),
+        ))
+    }
+}
+
+impl<R: AsyncBufRead + Unpin + Send> super::StreamPlatform for ConsoleChat<R> {
+    async fn next_event(&mut self) -> anyhow::Result<SteamEvent> {
+        loop {
+            tokio::time::sleep(self.interval).await;
+            let Some(line) = self.lines.next_line().await? else {
+                // stay connected, the agent may still be replying
+                log::info!("console chat ended");
+                return std::future::pending().await;
+            };
+            if let Some(event) = parse_line(&line) {
+                return Ok(event);
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_console_chat() {
+    use super::StreamPlatform;
+
+    let comment = |user: &str, content: &str| SteamEvent::Comment {
+        user: User::new(user, user),
+        content: content.to_string(),
+    };
+    let text =
+        "# a quiet evening\nbob: hi there\n\nno colon here\n小明：晚上好\nalice: what time: now?\n";
+    let mut chat = ConsoleChat::new(text.as_bytes(), Duration::ZERO);
+
+    assert_eq!(chat.next_event().await.unwrap(), comment("bob", "hi there"));
+    assert_eq!(chat.next_event().await.unwrap(), comment("小明", "晚上好"));
+    assert_eq!(
+        chat.next_event().await.unwrap(),
+        comment("alice", "what time: now?")
+    );
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), chat.next_event())
+            .await
+            .is_err()
+    );
+}
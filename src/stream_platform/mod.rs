@@ -1,72 +1,394 @@
-use std::collections::LinkedList;
+use std::{collections::LinkedList, sync::Arc};
+
+use crate::{
+    metrics::METRICS,
+    recorder::{self, Record},
+    state::StateStore,
+    status::Status,
+};
 
 pub mod bilibili;
+pub mod console;
+pub mod multi;
+pub mod replay;
 pub mod restream;
+pub mod twitch;
+
+/// A viewer, `id` is stable on its platform while `name` may change.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct User {
+    pub id: String,
+    pub name: String,
+}
+
+impl User {
+    pub fn new(id: impl ToString, name: impl Into<String>) -> Self {
+        Self {
+            id: id.to_string(),
+            name: name.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for User {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
 
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum SteamEvent {
-    Comment { user: String, content: String },
+    Comment {
+        user: User,
+        content: String,
+    },
+    Gift {
+        user: User,
+        gift: String,
+        count: u32,
+        amount: f64,
+        currency: String,
+    },
+    /// Paid message, e.g. bilibili SC, youtube super chat, twitch cheer.
+    SuperChat {
+        user: User,
+        content: String,
+        amount: f64,
+        currency: String,
+    },
+    Follow {
+        user: User,
+    },
+    /// Guard, channel membership or subscription.
+    Membership {
+        user: User,
+        level: String,
+        months: u32,
+        amount: f64,
+        currency: String,
+    },
+    Enter {
+        user: User,
+    },
+    /// `!command` from a moderator or the broadcaster.
+    Command {
+        user: User,
+        command: String,
+    },
+}
+
+impl SteamEvent {
+    /// Events that cost the viewer money. They are never dropped by `llm_loop`.
+    pub fn is_paid(&self) -> bool {
+        match self {
+            SteamEvent::SuperChat { .. } | SteamEvent::Membership { .. } => true,
+            SteamEvent::Gift { amount, .. } => *amount > 0.0,
+            _ => false,
+        }
+    }
+
+    /// Events worth waking the agent up for on their own.
+    pub fn need_reply(&self) -> bool {
+        !matches!(self, SteamEvent::Enter { .. })
+    }
+
+    pub fn user(&self) -> &User {
+        match self {
+            SteamEvent::Comment { user, .. }
+            | SteamEvent::Gift { user, .. }
+            | SteamEvent::SuperChat { user, .. }
+            | SteamEvent::Follow { user }
+            | SteamEvent::Membership { user, .. }
+            | SteamEvent::Enter { user }
+            | SteamEvent::Command { user, .. } => user,
+        }
+    }
+
+    /// Events worth interrupting the current reply for.
+    pub fn is_priority(&self) -> bool {
+        matches!(
+            self,
+            SteamEvent::SuperChat { .. } | SteamEvent::Command { .. }
+        )
+    }
+}
+
+impl std::fmt::Display for SteamEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SteamEvent::Comment { user, content } => write!(f, "{user}: {content}"),
+            SteamEvent::Gift {
+                user,
+                gift,
+                count,
+                amount,
+                currency,
+            } => {
+                write!(f, "[礼物] {user} 送出了 {count} 个 {gift}")?;
+                if *amount > 0.0 {
+                    write!(f, " ({amount:.2} {currency})")?;
+                }
+                Ok(())
+            }
+            SteamEvent::SuperChat {
+                user,
+                content,
+                amount,
+                currency,
+            } => write!(f, "[醒目留言 {amount:.2} {currency}] {user}: {content}"),
+            SteamEvent::Follow { user } => write!(f, "[关注] {user} 关注了直播间"),
+            SteamEvent::Membership {
+                user,
+                level,
+                months,
+                amount,
+                currency,
+            } => {
+                write!(f, "[会员] {user} 开通了 {level}")?;
+                if *months > 1 {
+                    write!(f, " x{months}")?;
+                }
+                if *amount > 0.0 {
+                    write!(f, " ({amount:.2} {currency})")?;
+                }
+                Ok(())
+            }
+            SteamEvent::Enter { user } => write!(f, "[进入] {user} 进入了直播间"),
+            SteamEvent::Command { user, command } => write!(f, "[管理员指令] {user}: {command}"),
+        }
+    }
+}
+
+/// A `SteamEvent` tagged with the platform it came from.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PlatformEvent {
+    /// The configured name, plus the source of relays, e.g. `restream/youtube`.
+    pub platform: String,
+    /// Where the user ids belong, e.g. `twitch` or `youtube`. Unlike `platform`
+    /// it doesn't change when a platform is renamed.
+    #[serde(default)]
+    pub source: String,
+    pub event: SteamEvent,
+}
+
+impl std::fmt::Display for PlatformEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}", self.platform, self.event)
+    }
 }
 
 pub trait StreamPlatform {
-    async fn next_event(&mut self) -> anyhow::Result<SteamEvent>;
+    fn next_event(
+        &mut self,
+    ) -> impl std::future::Future<Output = anyhow::Result<SteamEvent>> + Send;
+
+    /// Where the last event came from, for platforms relaying others.
+    fn source(&self) -> Option<&str> {
+        None
+    }
+}
+
+pub type CommentTx = tokio::sync::oneshot::Sender<LinkedList<PlatformEvent>>;
+pub type CommentRx = tokio::sync::oneshot::Receiver<LinkedList<PlatformEvent>>;
+
+/// Asks `llm_loop` for all stored events once one of them passes `wake`.
+/// A newer request replaces a pending one.
+pub struct CommentRequest {
+    pub tx: CommentTx,
+    pub wake: fn(&SteamEvent) -> bool,
+}
+
+/// Paid events may pile up to this many times `max_comment`.
+const PAID_OVERFLOW: usize = 4;
+
+/// Push an event, dropping the oldest unpaid event when over `max_comment`.
+/// Paid events are kept over `max_comment` on purpose, until there are
+/// `PAID_OVERFLOW` times as many, then the oldest goes. Returns the dropped event.
+fn store_event(
+    comment_store: &mut LinkedList<PlatformEvent>,
+    event: PlatformEvent,
+    max_comment: usize,
+) -> Option<PlatformEvent> {
+    comment_store.push_back(event);
+    if comment_store.len() <= max_comment {
+        return None;
+    }
+
+    let i = match comment_store.iter().position(|e| !e.event.is_paid()) {
+        Some(i) => i,
+        None if comment_store.len() > max_comment * PAID_OVERFLOW => {
+            log::warn!(
+                "{} paid events waiting, drop the oldest",
+                comment_store.len()
+            );
+            0
+        }
+        None => return None,
+    };
+    let mut tail = comment_store.split_off(i);
+    let dropped = tail.pop_front();
+    comment_store.append(&mut tail);
+    log::debug!("drop event: {:?}", dropped);
+    dropped
 }
 
-pub type CommentTx = tokio::sync::oneshot::Sender<LinkedList<(String, String)>>;
-#[allow(unused)]
-pub type CommentRx = tokio::sync::oneshot::Receiver<LinkedList<(String, String)>>;
+fn save_pending(state: &Option<Arc<StateStore>>, comment_store: &LinkedList<PlatformEvent>) {
+    if let Some(state) = state {
+        state.update(|s| s.pending_comments = comment_store.iter().cloned().collect());
+    }
+}
 
-pub async fn llm_loop<P: StreamPlatform>(
+pub async fn llm_loop(
     max_comment: usize,
-    mut rx: tokio::sync::mpsc::UnboundedReceiver<CommentTx>,
-    mut platform: P,
+    rx: &mut tokio::sync::mpsc::UnboundedReceiver<CommentRequest>,
+    mut platform: multi::MultiPlatform,
+    state: Option<Arc<StateStore>>,
+    status: Arc<Status>,
 ) -> anyhow::Result<()> {
-    let mut comment_store = LinkedList::new();
+    let mut comment_store: LinkedList<PlatformEvent> = state
+        .as_ref()
+        .and_then(|s| s.resume())
+        .map(|s| s.pending_comments.into_iter().collect())
+        .unwrap_or_default();
+    status.update(|s| s.pending_comments = comment_store.len());
+    let mut pending: Option<CommentRequest> = None;
 
     loop {
-        let r = tokio::select! {
-            tx = rx.recv()=>{
-                Ok(tx)
-            }
-            comment = platform.next_event() =>{
-                Err(comment)
-            }
-        };
-        match r {
-            Ok(Some(tx)) => {
-                if comment_store.is_empty() {
-                    log::info!("no comment, wait for platform");
-                    let SteamEvent::Comment { user, content } = platform
-                        .next_event()
-                        .await
-                        .map_err(|e| anyhow::anyhow!("platform error: {:?}", e))?;
-
-                    log::info!("comment: {} -> {}", user, content);
-                    let mut new_comment = LinkedList::new();
-                    new_comment.push_back((user, content));
-                    if let Err(e) = tx.send(new_comment) {
-                        log::warn!("send comment to tx failed");
-                        comment_store = e;
-                    }
-                } else {
-                    let mut new_comment = LinkedList::new();
-                    std::mem::swap(&mut new_comment, &mut comment_store);
-                    let _ = tx.send(new_comment);
+        if let Some(req) = pending.take() {
+            if req.tx.is_closed() {
+                log::debug!("comment request dropped");
+            } else if comment_store.iter().any(|e| (req.wake)(&e.event)) {
+                let new_comment = std::mem::take(&mut comment_store);
+                if let Err(e) = req.tx.send(new_comment) {
+                    log::warn!("send comment to tx failed");
+                    comment_store = e;
                 }
+                save_pending(&state, &comment_store);
+                status.update(|s| s.pending_comments = comment_store.len());
+            } else {
+                pending = Some(req);
             }
+        }
 
-            Err(Ok(SteamEvent::Comment { user, content })) => {
-                log::info!("comment: {} -> {}", user, content);
-                comment_store.push_back((user, content));
-                if comment_store.len() > max_comment {
-                    comment_store.pop_front();
-                }
-            }
-            Ok(None) => {
-                return Err(anyhow::anyhow!("platform rx closed"));
+        tokio::select! {
+            req = rx.recv() => {
+                let Some(req) = req else {
+                    // the agent is gone for good
+                    return Ok(());
+                };
+                pending = Some(req);
             }
-            Err(Err(e)) => {
-                return Err(anyhow::anyhow!("platform error: {:?}", e));
+            event = platform.next_event() => {
+                let event = event.map_err(|e| anyhow::anyhow!("platform error: {:?}", e))?;
+                log::info!("event: {}", event);
+                METRICS.comments.with_label_values(&[&event.platform]).inc();
+                recorder::record(Record::Comment { event: event.clone() });
+                if let Some(dropped) = store_event(&mut comment_store, event, max_comment) {
+                    METRICS
+                        .comments_dropped
+                        .with_label_values(&[&dropped.platform])
+                        .inc();
+                }
+                save_pending(&state, &comment_store);
+                status.update(|s| s.pending_comments = comment_store.len());
             }
         }
     }
 }
+
+#[test]
+fn test_store_event_keep_paid() {
+    let comment = |i: usize| PlatformEvent {
+        platform: "bilibili".to_string(),
+        source: "bilibili".to_string(),
+        event: SteamEvent::Comment {
+            user: User::new(i, format!("user{i}")),
+            content: "hi".to_string(),
+        },
+    };
+    let sc = PlatformEvent {
+        platform: "bilibili".to_string(),
+        source: "bilibili".to_string(),
+        event: SteamEvent::SuperChat {
+            user: User::new(1, "rich"),
+            content: "thanks".to_string(),
+            amount: 30.0,
+            currency: "CNY".to_string(),
+        },
+    };
+
+    let mut store = LinkedList::new();
+    store_event(&mut store, sc.clone(), 2);
+    for i in 0..5 {
+        store_event(&mut store, comment(i), 2);
+    }
+    assert_eq!(
+        store.into_iter().collect::<Vec<_>>(),
+        vec![sc.clone(), comment(4)]
+    );
+
+    // but not without limit
+    let mut store = LinkedList::new();
+    for _ in 0..2 * PAID_OVERFLOW {
+        assert_eq!(store_event(&mut store, sc.clone(), 2), None);
+    }
+    assert_eq!(store_event(&mut store, sc.clone(), 2), Some(sc));
+    assert_eq!(store.len(), 2 * PAID_OVERFLOW);
+}
+
+#[tokio::test]
+async fn test_llm_loop_wake() {
+    struct Fake(tokio::sync::mpsc::UnboundedReceiver<SteamEvent>);
+
+    impl StreamPlatform for Fake {
+        async fn next_event(&mut self) -> anyhow::Result<SteamEvent> {
+            self.0.recv().await.ok_or(anyhow::anyhow!("fake closed"))
+        }
+    }
+
+    let (event_tx, event_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut platforms = multi::MultiPlatform::new();
+    platforms.push("twitch", Fake(event_rx));
+    let (req_tx, req_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut req_rx = req_rx;
+    tokio::spawn(
+        async move { llm_loop(10, &mut req_rx, platforms, None, Default::default()).await },
+    );
+
+    let request = |wake: fn(&SteamEvent) -> bool| {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        req_tx.send(CommentRequest { tx, wake }).unwrap();
+        rx
+    };
+    let comment = SteamEvent::Comment {
+        user: User::new(2, "bob"),
+        content: "hi".to_string(),
+    };
+    let command = SteamEvent::Command {
+        user: User::new(3, "mod"),
+        command: "!skip".to_string(),
+    };
+
+    // a plain comment doesn't interrupt
+    let mut priority = request(SteamEvent::is_priority);
+    event_tx.send(comment.clone()).unwrap();
+    let r = tokio::time::timeout(std::time::Duration::from_millis(50), &mut priority).await;
+    assert!(r.is_err());
+
+    // the priority event takes the comment before it along
+    event_tx.send(command.clone()).unwrap();
+    let events = priority.await.unwrap();
+    assert_eq!(
+        events.into_iter().map(|e| e.event).collect::<Vec<_>>(),
+        vec![comment.clone(), command]
+    );
+
+    // a dropped request doesn't swallow events meant for the next one
+    drop(request(SteamEvent::is_priority));
+    event_tx.send(comment.clone()).unwrap();
+    let events = request(SteamEvent::need_reply).await.unwrap();
+    assert_eq!(
+        events.into_iter().map(|e| e.event).collect::<Vec<_>>(),
+        vec![comment]
+    );
+}
@@ -0,0 +1,224 @@
+use std::sync::Arc;
+
+use super::{PlatformEvent, SteamEvent, StreamPlatform};
+use crate::status::{PlatformState, Status};
+
+/// (tag, source, event)
+type SourceMsg = (String, String, anyhow::Result<SteamEvent>);
+
+/// Fan-in of several `StreamPlatform`s into one event stream.
+///
+/// Every platform runs in its own task, events are tagged with the name the
+/// platform was pushed with, plus the source for relays e.g. `restream/youtube`.
+/// A failing platform is dropped, the others keep going until all of them are
+/// gone.
+pub struct MultiPlatform {
+    tx: tokio::sync::mpsc::UnboundedSender<SourceMsg>,
+    rx: tokio::sync::mpsc::UnboundedReceiver<SourceMsg>,
+    alive: usize,
+    status: Arc<Status>,
+}
+
+impl Default for MultiPlatform {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MultiPlatform {
+    pub fn new() -> Self {
+        Self::with_status(Default::default())
+    }
+
+    /// Reports the platforms as connected until they fail.
+    pub fn with_status(status: Arc<Status>) -> Self {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        Self {
+            tx,
+            rx,
+            alive: 0,
+            status,
+        }
+    }
+
+    /// A platform whose type is its name.
+    #[cfg(test)]
+    pub fn push<P: StreamPlatform + Send + 'static>(&mut self, name: &str, platform: P) {
+        self.push_kind(name, name, platform)
+    }
+
+    /// A platform named `name` of type `kind`, the source of its events
+    /// unless it relays others.
+    pub fn push_kind<P: StreamPlatform + Send + 'static>(
+        &mut self,
+        name: &str,
+        kind: &str,
+        mut platform: P,
+    ) {
+        let tx = self.tx.clone();
+        let name = name.to_string();
+        let kind = kind.to_string();
+        self.alive += 1;
+        self.status.platform(&name, PlatformState::Connected);
+
+        tokio::spawn(async move {
+            loop {
+                let r = platform.next_event().await;
+                let is_err = r.is_err();
+                let (tag, source) = match platform.source() {
+                    Some(source) if !is_err => (format!("{name}/{source}"), source.to_string()),
+                    _ => (name.clone(), kind.clone()),
+                };
+                if tx.send((tag, source, r)).is_err() || is_err {
+                    break;
+                }
+            }
+        });
+    }
+
+    pub async fn next_event(&mut self) -> anyhow::Result<PlatformEvent> {
+        loop {
+            if self.alive == 0 {
+                return Err(anyhow::anyhow!("no platform alive"));
+            }
+
+            let (platform, source, r) = self
+                .rx
+                .recv()
+                .await
+                .ok_or(anyhow::anyhow!("platform rx closed"))?;
+            match r {
+                Ok(event) => {
+                    return Ok(PlatformEvent {
+                        platform,
+                        source,
+                        event,
+                    })
+                }
+                Err(e) => {
+                    self.alive -= 1;
+                    log::error!("platform {} error: {:?}", platform, e);
+                    self.status.platform(&platform, PlatformState::Disconnected);
+                    self.status.error(&platform, &e);
+                    if self.alive == 0 {
+                        return Err(anyhow::anyhow!("platform {} error: {:?}", platform, e));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_multi_platform() {
+    struct Fake(Vec<SteamEvent>);
+
+    impl StreamPlatform for Fake {
+        async fn next_event(&mut self) -> anyhow::Result<SteamEvent> {
+            if self.0.is_empty() {
+                Err(anyhow::anyhow!("fake closed"))
+            } else {
+                Ok(self.0.remove(0))
+            }
+        }
+    }
+
+    let comment = |content: &str| SteamEvent::Comment {
+        user: super::User::new(1, "bob"),
+        content: content.to_string(),
+    };
+
+    let status = Arc::new(Status::default());
+    let mut platforms = MultiPlatform::with_status(status.clone());
+    platforms.push("bilibili", Fake(vec![comment("a"), comment("b")]));
+    platforms.push("youtube", Fake(vec![comment("c")]));
+
+    let mut events = vec![];
+    while let Ok(e) = platforms.next_event().await {
+        events.push(e);
+    }
+    assert_eq!(events.len(), 3);
+    let report = status.report();
+    assert_eq!(report.platforms["youtube"], PlatformState::Disconnected);
+    assert_eq!(report.last_error.unwrap().message, "fake closed");
+
+    let from = |p: &str| {
+        events
+            .iter()
+            .filter(|e| e.platform == p)
+            .map(|e| e.event.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(from("bilibili"), vec![comment("a"), comment("b")]);
+    assert_eq!(from("youtube"), vec![comment("c")]);
+}
+
+#[tokio::test]
+async fn test_multi_platform_source() {
+    struct Relay(Vec<(&'static str, SteamEvent)>, Option<&'static str>);
+
+    impl StreamPlatform for Relay {
+        async fn next_event(&mut self) -> anyhow::Result<SteamEvent> {
+            if self.0.is_empty() {
+                Err(anyhow::anyhow!("relay closed"))
+            } else {
+                let (source, event) = self.0.remove(0);
+                self.1 = Some(source);
+                Ok(event)
+            }
+        }
+
+        fn source(&self) -> Option<&str> {
+            self.1
+        }
+    }
+
+    let comment = SteamEvent::Comment {
+        user: super::User::new(1, "bob"),
+        content: "hi".to_string(),
+    };
+    let status = Arc::new(Status::default());
+    let mut platforms = MultiPlatform::with_status(status.clone());
+    platforms.push(
+        "restream",
+        Relay(
+            vec![("youtube", comment.clone()), ("twitch", comment.clone())],
+            None,
+        ),
+    );
+
+    let mut tags = vec![];
+    while let Ok(e) = platforms.next_event().await {
+        tags.push((e.platform, e.source));
+    }
+    let tag = |tag: &str, source: &str| (tag.to_string(), source.to_string());
+    assert_eq!(
+        tags,
+        vec![
+            tag("restream/youtube", "youtube"),
+            tag("restream/twitch", "twitch")
+        ]
+    );
+
+    // a platform relaying nothing is the source of its events, by type not name
+    struct Plain(Vec<SteamEvent>);
+
+    impl StreamPlatform for Plain {
+        async fn next_event(&mut self) -> anyhow::Result<SteamEvent> {
+            self.0.pop().ok_or(anyhow::anyhow!("plain closed"))
+        }
+    }
+
+    let mut platforms = MultiPlatform::new();
+    platforms.push_kind("twitch_en", "twitch", Plain(vec![comment]));
+    let e = platforms.next_event().await.unwrap();
+    assert_eq!(
+        (e.platform.as_str(), e.source.as_str()),
+        ("twitch_en", "twitch")
+    );
+    // the relay itself is what disconnects
+    assert_eq!(
+        status.report().platforms["restream"],
+        PlatformState::Disconnected
+    );
+}
@@ -0,0 +1,75 @@
+//! Plays back the chat of a recorded session.
+
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use super::SteamEvent;
+
+pub struct ReplayPlatform {
+    /// Offsets from the start of the recording.
+    events: VecDeque<(Duration, SteamEvent)>,
+    start: tokio::time::Instant,
+    speed: f64,
+    /// Platforms still replaying.
+    remaining: Arc<tokio::sync::watch::Sender<usize>>,
+}
+
+impl ReplayPlatform {
+    /// `speed` 2.0 replays twice as fast, 0 without waiting.
+    pub fn new(
+        events: VecDeque<(Duration, SteamEvent)>,
+        start: tokio::time::Instant,
+        speed: f64,
+        remaining: Arc<tokio::sync::watch::Sender<usize>>,
+    ) -> Self {
+        Self {
+            events,
+            start,
+            speed,
+            remaining,
+        }
+    }
+}
+
+impl super::StreamPlatform for ReplayPlatform {
+    async fn next_event(&mut self) -> anyhow::Result<SteamEvent> {
+        let Some((offset, event)) = self.events.pop_front() else {
+            // stay connected, the agent may still be replying
+            self.remaining.send_modify(|n| *n = n.saturating_sub(1));
+            return std::future::pending().await;
+        };
+        if self.speed > 0.0 {
+            tokio::time::sleep_until(self.start + offset.div_f64(self.speed)).await;
+        }
+        Ok(event)
+    }
+}
+
+#[tokio::test]
+async fn test_replay_platform() {
+    use super::{StreamPlatform, User};
+
+    let comment = |content: &str| SteamEvent::Comment {
+        user: User::new(1, "bob"),
+        content: content.to_string(),
+    };
+    let events = [(0, comment("a")), (400, comment("b")), (800, comment("c"))]
+        .into_iter()
+        .map(|(ms, e)| (Duration::from_millis(ms), e))
+        .collect();
+    let (remaining, mut done) = tokio::sync::watch::channel(1);
+    let start = tokio::time::Instant::now();
+    let mut platform = ReplayPlatform::new(events, start, 4.0, Arc::new(remaining));
+
+    for (content, ms) in [("a", 0), ("b", 100), ("c", 200)] {
+        assert_eq!(platform.next_event().await.unwrap(), comment(content));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(ms) && elapsed < Duration::from_millis(ms + 80));
+    }
+
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), platform.next_event())
+            .await
+            .is_err()
+    );
+    done.wait_for(|n| *n == 0).await.unwrap();
+}
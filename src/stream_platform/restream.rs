@@ -1,23 +1,100 @@
-use futures_util::stream::StreamExt;
-use tokio::net::TcpStream;
-use tokio_websockets::MaybeTlsStream;
+use std::time::Duration;
+
+use futures_util::{stream::StreamExt, SinkExt};
+use tokio::{net::TcpStream, time::Instant};
+use tokio_websockets::{MaybeTlsStream, Message};
+
+use crate::supervisor::backoff;
+
+type WsClient = tokio_websockets::WebSocketStream<MaybeTlsStream<TcpStream>>;
 
 pub struct RestreamChat {
-    client: tokio_websockets::WebSocketStream<MaybeTlsStream<TcpStream>>,
+    uri: String,
+    client: WsClient,
+    max_retry: u32,
+    ping_interval: Duration,
+    backoff_base: Duration,
+    last_seen: Instant,
+    /// Platform of the last event, e.g. `youtube`.
+    source: Option<String>,
 }
 
 impl RestreamChat {
-    pub async fn new(uri: &str) -> anyhow::Result<Self> {
+    async fn connect(uri: &str) -> anyhow::Result<WsClient> {
         let (client, resp) = tokio_websockets::ClientBuilder::new()
             .uri(uri)?
             .connect()
             .await?;
         if resp.status() == 101 {
-            Ok(Self { client })
+            Ok(client)
         } else {
             Err(anyhow::anyhow!("Failed to connect to {} {:?}", uri, resp))
         }
     }
+
+    pub async fn new(uri: &str, max_retry: u32, ping_interval: Duration) -> anyhow::Result<Self> {
+        let client = Self::connect(uri).await?;
+        Ok(Self {
+            uri: uri.to_string(),
+            client,
+            max_retry,
+            ping_interval,
+            backoff_base: Duration::from_secs(1),
+            last_seen: Instant::now(),
+            source: None,
+        })
+    }
+
+    /// Next ws message, pinging the server while idle.
+    /// Fails if nothing (not even a pong) arrives within two ping intervals.
+    async fn next_message(&mut self) -> anyhow::Result<Message> {
+        let mut ping =
+            tokio::time::interval_at(Instant::now() + self.ping_interval, self.ping_interval);
+        loop {
+            tokio::select! {
+                msg = self.client.next() => {
+                    let msg = msg.ok_or(anyhow::anyhow!("restream ws closed!"))??;
+                    self.last_seen = Instant::now();
+                    return Ok(msg);
+                }
+                _ = ping.tick() => {
+                    if self.last_seen.elapsed() > self.ping_interval * 2 {
+                        return Err(anyhow::anyhow!("restream ws ping timeout"));
+                    }
+                    self.client.send(Message::ping("")).await?;
+                }
+            }
+        }
+    }
+
+    async fn reconnect(&mut self) -> anyhow::Result<()> {
+        for attempt in 0..self.max_retry {
+            let delay = backoff(attempt, self.backoff_base);
+            log::info!(
+                "restream reconnect in {:?} ({}/{})",
+                delay,
+                attempt + 1,
+                self.max_retry
+            );
+            tokio::time::sleep(delay).await;
+
+            match Self::connect(&self.uri).await {
+                Ok(client) => {
+                    log::info!("restream reconnected");
+                    self.client = client;
+                    self.last_seen = Instant::now();
+                    return Ok(());
+                }
+                Err(e) => {
+                    log::warn!("restream reconnect failed: {:?}", e);
+                }
+            }
+        }
+        Err(anyhow::anyhow!(
+            "restream ws closed! gave up after {} retries",
+            self.max_retry
+        ))
+    }
 }
 
 #[allow(unused)]
@@ -47,48 +124,194 @@ struct RestreamPayload {
     user_id: u64,
 }
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct RestreamEventPayload {
+    #[serde(default)]
     author: Author,
     #[serde(default)]
     text: String,
+    /// twitch cheer
+    #[serde(default)]
+    bits: Option<u64>,
+    /// paid message, e.g. youtube super chat
+    #[serde(default)]
+    amount: Option<f64>,
+    #[serde(default)]
+    currency: Option<String>,
+    /// subscription / membership
+    #[serde(default)]
+    months: Option<u32>,
+    #[serde(default)]
+    tier: Option<String>,
+    /// gifted subscriptions
+    #[serde(default)]
+    count: Option<u32>,
 }
 
 #[allow(unused)]
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, Default, serde::Deserialize)]
 struct Author {
     #[serde(default)]
     id: String,
     #[serde(default)]
     #[serde(rename = "displayName")]
     name: String,
+    #[serde(default)]
+    #[serde(rename = "isChatModerator")]
+    is_moderator: bool,
+    #[serde(default)]
+    #[serde(rename = "isChatOwner")]
+    is_owner: bool,
+}
+
+/// `eventTypeId` of the restream chat events.
+mod event_type {
+    pub const TWITCH_TEXT: u32 = 4;
+    pub const YOUTUBE_TEXT: u32 = 5;
+    pub const YOUTUBE_SUPER_CHAT: u32 = 6;
+    pub const YOUTUBE_SUPER_STICKER: u32 = 7;
+    pub const YOUTUBE_SPONSOR: u32 = 8;
+    pub const TWITCH_FOLLOW: u32 = 11;
+    pub const TWITCH_SUBSCRIPTION: u32 = 12;
+    pub const TWITCH_GIFTED_SUBSCRIPTION: u32 = 13;
+    pub const TWITCH_CHEER: u32 = 14;
+}
+
+impl RestreamPayload {
+    /// The relayed platform, from a `connectionIdentifier` like
+    /// `9209300-youtube-fyoek8R2vEQ`.
+    fn source(&self) -> Option<&str> {
+        self.connection_identifier
+            .split('-')
+            .nth(1)
+            .filter(|s| !s.is_empty())
+    }
+}
+
+impl RestreamEvent {
+    fn into_steam_event(self) -> Option<super::SteamEvent> {
+        use super::SteamEvent;
+        use event_type::*;
+
+        let type_id = self.payload.event_type_id;
+        let RestreamEventPayload {
+            author,
+            text,
+            bits,
+            amount,
+            currency,
+            months,
+            tier,
+            count,
+        } = self.payload.event_payload;
+        let is_mod = author.is_moderator || author.is_owner;
+        let user = super::User::new(author.id, author.name);
+
+        match type_id {
+            YOUTUBE_SUPER_CHAT => Some(SteamEvent::SuperChat {
+                user,
+                content: text,
+                amount: amount.unwrap_or_default(),
+                currency: currency.unwrap_or_default(),
+            }),
+            YOUTUBE_SUPER_STICKER => Some(SteamEvent::Gift {
+                user,
+                gift: "Super Sticker".to_string(),
+                count: 1,
+                amount: amount.unwrap_or_default(),
+                currency: currency.unwrap_or_default(),
+            }),
+            TWITCH_CHEER => Some(SteamEvent::SuperChat {
+                user,
+                content: text,
+                amount: bits.unwrap_or_default() as f64 / 100.0,
+                currency: "USD".to_string(),
+            }),
+            YOUTUBE_SPONSOR | TWITCH_SUBSCRIPTION => Some(SteamEvent::Membership {
+                user,
+                level: tier.unwrap_or_default(),
+                months: months.unwrap_or(1),
+                amount: amount.unwrap_or_default(),
+                currency: currency.unwrap_or_default(),
+            }),
+            // a membership like twitch's own subgift, so it's always kept as paid
+            TWITCH_GIFTED_SUBSCRIPTION => Some(SteamEvent::Membership {
+                user,
+                level: format!(
+                    "{} (赠送 {} 份)",
+                    tier.unwrap_or_default(),
+                    count.unwrap_or(1)
+                )
+                .trim()
+                .to_string(),
+                months: 1,
+                amount: amount.unwrap_or_default(),
+                currency: currency.unwrap_or_default(),
+            }),
+            TWITCH_FOLLOW => Some(SteamEvent::Follow { user }),
+            _ if is_mod && text.starts_with('!') => Some(SteamEvent::Command {
+                user,
+                command: text,
+            }),
+            TWITCH_TEXT | YOUTUBE_TEXT if !text.is_empty() => Some(SteamEvent::Comment {
+                user,
+                content: text,
+            }),
+            // text messages of the platforms not listed above
+            _ if !text.is_empty() => {
+                log::debug!("restream event type {} read as a comment", type_id);
+                Some(SteamEvent::Comment {
+                    user,
+                    content: text,
+                })
+            }
+            _ => {
+                log::info!("unhandled restream event type {} from {}", type_id, user);
+                None
+            }
+        }
+    }
 }
 
 impl super::StreamPlatform for RestreamChat {
     async fn next_event(&mut self) -> anyhow::Result<super::SteamEvent> {
         loop {
-            let msg = self
-                .client
-                .next()
-                .await
-                .ok_or(anyhow::anyhow!("restream ws closed!"))??;
+            let msg = match self.next_message().await {
+                Ok(msg) => msg,
+                Err(e) => {
+                    log::warn!("restream ws error: {:?}", e);
+                    self.reconnect().await?;
+                    continue;
+                }
+            };
             if let Some(text) = msg.as_text() {
-                if let Ok(event) = serde_json::from_str::<RestreamEvent>(&text) {
-                    return Ok(super::SteamEvent::Comment {
-                        user: event.payload.event_payload.author.name,
-                        content: event.payload.event_payload.text,
-                    });
+                if let Ok(event) = serde_json::from_str::<RestreamEvent>(text) {
+                    let source = event.payload.source().map(str::to_string);
+                    if let Some(event) = event.into_steam_event() {
+                        self.source = source;
+                        return Ok(event);
+                    }
                 } else {
                     log::debug!("Failed to parse restream event: {}", text);
                 }
             }
         }
     }
+
+    fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
 }
 
 impl RestreamChat {
     pub async fn from_config(config: crate::config::RestreamConfig) -> anyhow::Result<Self> {
-        Ok(Self::new(&config.url).await?)
+        Self::new(
+            &config.url,
+            config.max_retry,
+            Duration::from_secs(config.ping_interval_secs.max(1)),
+        )
+        .await
     }
 }
 
@@ -97,4 +320,236 @@ fn test() {
     let json = r#"{"action":"event","payload":{"connectionIdentifier":"9209300-youtube-fyoek8R2vEQ","eventIdentifier":"cbaa276f8c5a583412fda48e7eff2bb2","eventPayload":{"author":{"avatar":"https://yt3.ggpht.com/ytc/AIdro_ldJnZ7mv3rFn-tjv592UgHNAiyYH_VZ-mvhJU2F94=s120-c-k-c0x00ffffff-no-rj","displayName":"Vivian Hu","id":"UC7xT5iEi6tzxdY6w1L2PI3A","isChatModerator":false,"isChatOwner":false,"isChatSponsor":false,"isVerified":false},"bot":false,"liveChatMessageId":"LCC.EhwKGkNQMldwS3lNMVlzREZUOF9yUVlkX0lrNkl3","text":"test"},"eventSourceId":13,"eventTypeId":5,"userId":9209300},"timestamp":1740152273}"#;
     let event = serde_json::from_str::<RestreamEvent>(json);
     println!("{:?}", event);
+    let event = event.unwrap();
+    assert_eq!(event.payload.source(), Some("youtube"));
+    assert_eq!(
+        event.into_steam_event(),
+        Some(super::SteamEvent::Comment {
+            user: super::User::new("UC7xT5iEi6tzxdY6w1L2PI3A", "Vivian Hu"),
+            content: "test".to_string(),
+        })
+    );
+
+    let json = r#"{"action":"event","payload":{"eventPayload":{"author":{"displayName":"Vivian Hu"},"text":"great stream","amount":5.0,"currency":"USD"},"eventSourceId":13,"eventTypeId":6}}"#;
+    let event = serde_json::from_str::<RestreamEvent>(json).unwrap();
+    assert_eq!(event.payload.source(), None);
+    assert_eq!(
+        event.into_steam_event(),
+        Some(super::SteamEvent::SuperChat {
+            user: super::User::new("", "Vivian Hu"),
+            content: "great stream".to_string(),
+            amount: 5.0,
+            currency: "USD".to_string(),
+        })
+    );
+
+    let json = r#"{"action":"event","payload":{"eventPayload":{"author":{"displayName":"Vivian Hu","isChatModerator":true},"text":"!skip"},"eventSourceId":13,"eventTypeId":5}}"#;
+    let event = serde_json::from_str::<RestreamEvent>(json).unwrap();
+    assert_eq!(
+        event.into_steam_event(),
+        Some(super::SteamEvent::Command {
+            user: super::User::new("", "Vivian Hu"),
+            command: "!skip".to_string(),
+        })
+    );
+}
+
+/// `eventPayload` with the author bob, as the event `type_id`.
+#[cfg(test)]
+fn restream_event(type_id: u32, payload: &str) -> Option<super::SteamEvent> {
+    let mut payload: serde_json::Value = serde_json::from_str(&format!("{{{payload}}}")).unwrap();
+    payload["author"] = serde_json::json!({"displayName": "bob", "id": "1"});
+    let json = serde_json::json!({
+        "action": "event",
+        "payload": {"eventPayload": payload, "eventTypeId": type_id}
+    });
+    serde_json::from_value::<RestreamEvent>(json)
+        .unwrap()
+        .into_steam_event()
+}
+
+#[test]
+fn test_twitch_text() {
+    assert_eq!(
+        restream_event(event_type::TWITCH_TEXT, r#""text":"hi""#),
+        Some(super::SteamEvent::Comment {
+            user: super::User::new("1", "bob"),
+            content: "hi".to_string(),
+        })
+    );
+}
+
+#[test]
+fn test_youtube_text() {
+    // a text message is not paid whatever it carries
+    assert_eq!(
+        restream_event(
+            event_type::YOUTUBE_TEXT,
+            r#""text":"hi","amount":5.0,"currency":"USD""#
+        ),
+        Some(super::SteamEvent::Comment {
+            user: super::User::new("1", "bob"),
+            content: "hi".to_string(),
+        })
+    );
+}
+
+#[test]
+fn test_youtube_super_chat() {
+    assert_eq!(
+        restream_event(
+            event_type::YOUTUBE_SUPER_CHAT,
+            r#""text":"great stream","amount":5.0,"currency":"USD""#
+        ),
+        Some(super::SteamEvent::SuperChat {
+            user: super::User::new("1", "bob"),
+            content: "great stream".to_string(),
+            amount: 5.0,
+            currency: "USD".to_string(),
+        })
+    );
+}
+
+#[test]
+fn test_youtube_super_sticker() {
+    assert_eq!(
+        restream_event(
+            event_type::YOUTUBE_SUPER_STICKER,
+            r#""amount":2.0,"currency":"EUR""#
+        ),
+        Some(super::SteamEvent::Gift {
+            user: super::User::new("1", "bob"),
+            gift: "Super Sticker".to_string(),
+            count: 1,
+            amount: 2.0,
+            currency: "EUR".to_string(),
+        })
+    );
+}
+
+#[test]
+fn test_youtube_sponsor() {
+    assert_eq!(
+        restream_event(event_type::YOUTUBE_SPONSOR, r#""tier":"Member","months":3"#),
+        Some(super::SteamEvent::Membership {
+            user: super::User::new("1", "bob"),
+            level: "Member".to_string(),
+            months: 3,
+            amount: 0.0,
+            currency: String::new(),
+        })
+    );
+}
+
+#[test]
+fn test_twitch_follow() {
+    assert_eq!(
+        restream_event(event_type::TWITCH_FOLLOW, ""),
+        Some(super::SteamEvent::Follow {
+            user: super::User::new("1", "bob"),
+        })
+    );
+}
+
+#[test]
+fn test_twitch_subscription() {
+    assert_eq!(
+        restream_event(
+            event_type::TWITCH_SUBSCRIPTION,
+            r#""tier":"Tier 1","months":1,"amount":4.99,"currency":"USD""#
+        ),
+        Some(super::SteamEvent::Membership {
+            user: super::User::new("1", "bob"),
+            level: "Tier 1".to_string(),
+            months: 1,
+            amount: 4.99,
+            currency: "USD".to_string(),
+        })
+    );
+}
+
+#[test]
+fn test_twitch_gifted_subscription() {
+    assert_eq!(
+        restream_event(
+            event_type::TWITCH_GIFTED_SUBSCRIPTION,
+            r#""tier":"Tier 1","count":5"#
+        ),
+        Some(super::SteamEvent::Membership {
+            user: super::User::new("1", "bob"),
+            level: "Tier 1 (赠送 5 份)".to_string(),
+            months: 1,
+            amount: 0.0,
+            currency: String::new(),
+        })
+    );
+    let gifted = restream_event(event_type::TWITCH_GIFTED_SUBSCRIPTION, r#""count":5"#);
+    assert!(gifted.unwrap().is_paid());
+}
+
+#[test]
+fn test_twitch_cheer() {
+    assert_eq!(
+        restream_event(
+            event_type::TWITCH_CHEER,
+            r#""text":"cheer100 gg","bits":100"#
+        ),
+        Some(super::SteamEvent::SuperChat {
+            user: super::User::new("1", "bob"),
+            content: "cheer100 gg".to_string(),
+            amount: 1.0,
+            currency: "USD".to_string(),
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_reconnect() {
+    use super::{SteamEvent, StreamPlatform};
+    use axum::extract::ws::{Message as WsMessage, WebSocketUpgrade};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    let event = |text: &str| {
+        format!(
+            r#"{{"action":"event","payload":{{"eventPayload":{{"author":{{"displayName":"bob"}},"text":"{text}"}},"eventTypeId":5}}}}"#
+        )
+    };
+    let connections = Arc::new(AtomicUsize::new(0));
+    let connections_ = connections.clone();
+
+    // every connection sends one event and hangs up
+    let app = axum::Router::new().route(
+        "/",
+        axum::routing::any(move |ws: WebSocketUpgrade| {
+            let n = connections_.fetch_add(1, Ordering::SeqCst);
+            let msg = event(&format!("hello {n}"));
+            async move {
+                ws.on_upgrade(move |mut socket| async move {
+                    let _ = socket.send(WsMessage::Text(msg.into())).await;
+                })
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let mut chat = RestreamChat::new(&format!("ws://{addr}/"), 3, Duration::from_secs(30))
+        .await
+        .unwrap();
+    chat.backoff_base = Duration::from_millis(10);
+
+    for i in 0..3 {
+        assert_eq!(
+            chat.next_event().await.unwrap(),
+            SteamEvent::Comment {
+                user: super::User::new("", "bob"),
+                content: format!("hello {i}"),
+            }
+        );
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
 }
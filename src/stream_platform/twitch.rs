@@ -0,0 +1,585 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_websockets::{MaybeTlsStream, Message};
+
+use crate::supervisor::backoff;
+
+type WsClient = tokio_websockets::WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A single IRC line, with the IRCv3 tags Twitch attaches to it.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct IrcMessage {
+    pub tags: HashMap<String, String>,
+    pub prefix: Option<String>,
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+fn unescape_tag_value(value: &str) -> String {
+    let mut s = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => s.push(';'),
+            Some('s') => s.push(' '),
+            Some('r') => s.push('\r'),
+            Some('n') => s.push('\n'),
+            Some(c) => s.push(c),
+            None => {}
+        }
+    }
+    s
+}
+
+impl IrcMessage {
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut msg = IrcMessage::default();
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+
+        if let Some(tags) = rest.strip_prefix('@') {
+            let (tags, r) = tags.split_once(' ')?;
+            for tag in tags.split(';') {
+                let (k, v) = tag.split_once('=').unwrap_or((tag, ""));
+                msg.tags.insert(k.to_string(), unescape_tag_value(v));
+            }
+            rest = r.trim_start();
+        }
+
+        if let Some(prefix) = rest.strip_prefix(':') {
+            let (prefix, r) = prefix.split_once(' ')?;
+            msg.prefix = Some(prefix.to_string());
+            rest = r.trim_start();
+        }
+
+        let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        if command.is_empty() {
+            return None;
+        }
+        msg.command = command.to_string();
+
+        while !rest.is_empty() {
+            if let Some(trailing) = rest.strip_prefix(':') {
+                msg.params.push(trailing.to_string());
+                break;
+            }
+            let (param, r) = rest.split_once(' ').unwrap_or((rest, ""));
+            msg.params.push(param.to_string());
+            rest = r.trim_start();
+        }
+
+        Some(msg)
+    }
+
+    /// The nick from a `nick!user@host` prefix.
+    pub fn nick(&self) -> Option<&str> {
+        let prefix = self.prefix.as_deref()?;
+        Some(prefix.split_once('!').map(|(n, _)| n).unwrap_or(prefix))
+    }
+
+    /// `display-name` tag, falling back to the nick.
+    pub fn display_name(&self) -> String {
+        match self.tags.get("display-name") {
+            Some(name) if !name.is_empty() => name.clone(),
+            _ => self.nick().unwrap_or_default().to_string(),
+        }
+    }
+
+    /// `user-id` tag, falling back to the nick.
+    pub fn user(&self) -> super::User {
+        match self.tags.get("user-id") {
+            Some(id) if !id.is_empty() => super::User::new(id, self.display_name()),
+            _ => {
+                let login = self.tags.get("login").map(|l| l.as_str());
+                super::User::new(
+                    login.or(self.nick()).unwrap_or_default(),
+                    self.display_name(),
+                )
+            }
+        }
+    }
+
+    /// `badges=moderator/1,subscriber/12` -> [("moderator", "1"), ("subscriber", "12")]
+    pub fn badges(&self) -> Vec<(&str, &str)> {
+        self.tags
+            .get("badges")
+            .map(|b| {
+                b.split(',')
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.split_once('/').unwrap_or((s, "")))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.tags
+            .get("bits")
+            .and_then(|b| b.parse().ok())
+            .unwrap_or(0)
+    }
+
+    pub fn is_mod(&self) -> bool {
+        self.tags.get("mod").map(|m| m == "1").unwrap_or(false)
+            || self
+                .badges()
+                .iter()
+                .any(|(b, _)| *b == "moderator" || *b == "broadcaster")
+    }
+
+    /// sub, resub and gifted subs from a USERNOTICE. A gifted sub is the
+    /// gifter's, with the recipient in the level.
+    pub fn membership(&self) -> Option<super::SteamEvent> {
+        let msg_id = self.tags.get("msg-id")?;
+        let recipient = match msg_id.as_str() {
+            "sub" | "resub" => None,
+            "subgift" => self.tags.get("msg-param-recipient-display-name"),
+            _ => return None,
+        };
+        let plan = self
+            .tags
+            .get("msg-param-sub-plan")
+            .map(|s| s.as_str())
+            .unwrap_or("1000");
+        let (level, amount) = match plan {
+            "Prime" => ("Prime", 0.0),
+            "2000" => ("Tier 2", 9.99),
+            "3000" => ("Tier 3", 24.99),
+            _ => ("Tier 1", 4.99),
+        };
+        let months = self
+            .tags
+            .get("msg-param-cumulative-months")
+            .and_then(|m| m.parse().ok())
+            .unwrap_or(1);
+        let level = match recipient {
+            Some(recipient) => format!("{level} (赠送给 {recipient})"),
+            None => level.to_string(),
+        };
+        Some(super::SteamEvent::Membership {
+            user: self.user(),
+            level,
+            months,
+            amount,
+            currency: "USD".to_string(),
+        })
+    }
+
+    pub fn text(&self) -> &str {
+        self.params.last().map(|s| s.as_str()).unwrap_or_default()
+    }
+}
+
+pub struct TwitchChat {
+    uri: String,
+    nick: String,
+    token: String,
+    channel: String,
+    client: WsClient,
+    pending: VecDeque<IrcMessage>,
+    max_retry: u32,
+    backoff_base: Duration,
+}
+
+impl TwitchChat {
+    async fn connect(uri: &str) -> anyhow::Result<WsClient> {
+        let (client, resp) = tokio_websockets::ClientBuilder::new()
+            .uri(uri)?
+            .connect()
+            .await?;
+        if resp.status() != 101 {
+            return Err(anyhow::anyhow!("Failed to connect to {} {:?}", uri, resp));
+        }
+        Ok(client)
+    }
+
+    pub async fn new(
+        uri: &str,
+        nick: &str,
+        token: &str,
+        channel: &str,
+        max_retry: u32,
+    ) -> anyhow::Result<Self> {
+        let client = Self::connect(uri).await?;
+        let mut chat = Self {
+            uri: uri.to_string(),
+            nick: nick.to_lowercase(),
+            token: token.trim_start_matches("oauth:").to_string(),
+            channel: channel.trim_start_matches('#').to_lowercase(),
+            client,
+            pending: VecDeque::new(),
+            max_retry,
+            backoff_base: Duration::from_secs(1),
+        };
+        chat.login().await?;
+        Ok(chat)
+    }
+
+    async fn login(&mut self) -> anyhow::Result<()> {
+        self.send_line("CAP REQ :twitch.tv/tags twitch.tv/commands")
+            .await?;
+        if self.token.is_empty() {
+            // anonymous login, read only
+            self.send_line("PASS SCHMOOPIIE").await?;
+        } else {
+            let pass = format!("PASS oauth:{}", self.token);
+            self.send_line(&pass).await?;
+        }
+        let nick = format!("NICK {}", self.nick);
+        self.send_line(&nick).await?;
+        let join = format!("JOIN #{}", self.channel);
+        self.send_line(&join).await?;
+        Ok(())
+    }
+
+    /// Connects again and rejoins the channel, when the connection drops or
+    /// twitch sends RECONNECT before a server restart.
+    async fn reconnect(&mut self) -> anyhow::Result<()> {
+        for attempt in 0..self.max_retry {
+            let delay = backoff(attempt, self.backoff_base);
+            log::info!(
+                "twitch reconnect in {:?} ({}/{})",
+                delay,
+                attempt + 1,
+                self.max_retry
+            );
+            tokio::time::sleep(delay).await;
+
+            match Self::connect(&self.uri).await {
+                Ok(client) => {
+                    self.client = client;
+                    match self.login().await {
+                        Ok(()) => {
+                            log::info!("twitch reconnected to #{}", self.channel);
+                            return Ok(());
+                        }
+                        Err(e) => log::warn!("twitch rejoin failed: {:?}", e),
+                    }
+                }
+                Err(e) => {
+                    log::warn!("twitch reconnect failed: {:?}", e);
+                }
+            }
+        }
+        Err(anyhow::anyhow!(
+            "twitch reconnect gave up after {} retries",
+            self.max_retry
+        ))
+    }
+
+    async fn send_line(&mut self, line: &str) -> anyhow::Result<()> {
+        self.client.send(Message::text(line.to_string())).await?;
+        Ok(())
+    }
+
+    pub async fn next_irc_message(&mut self) -> anyhow::Result<IrcMessage> {
+        loop {
+            if let Some(msg) = self.pending.pop_front() {
+                return Ok(msg);
+            }
+
+            let msg = match self.client.next().await {
+                Some(Ok(msg)) => msg,
+                Some(Err(e)) => {
+                    log::warn!("twitch ws error: {:?}", e);
+                    self.reconnect().await?;
+                    continue;
+                }
+                None => {
+                    log::warn!("twitch ws closed!");
+                    self.reconnect().await?;
+                    continue;
+                }
+            };
+            if let Some(text) = msg.as_text() {
+                // a single frame may carry several \r\n separated lines
+                for line in text.lines().filter(|l| !l.trim().is_empty()) {
+                    match IrcMessage::parse(line) {
+                        Some(msg) => self.pending.push_back(msg),
+                        None => log::debug!("Failed to parse twitch irc line: {}", line),
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl super::StreamPlatform for TwitchChat {
+    async fn next_event(&mut self) -> anyhow::Result<super::SteamEvent> {
+        loop {
+            let msg = self.next_irc_message().await?;
+            match msg.command.as_str() {
+                "PING" => {
+                    let server = msg.text().to_string();
+                    self.send_line(&format!("PONG :{server}")).await?;
+                }
+                "PRIVMSG" => {
+                    log::debug!(
+                        "twitch privmsg badges:{:?} bits:{} mod:{}",
+                        msg.badges(),
+                        msg.bits(),
+                        msg.is_mod()
+                    );
+                    let bits = msg.bits();
+                    if bits > 0 {
+                        return Ok(super::SteamEvent::SuperChat {
+                            user: msg.user(),
+                            content: msg.text().to_string(),
+                            amount: bits as f64 / 100.0,
+                            currency: "USD".to_string(),
+                        });
+                    }
+                    if msg.is_mod() && msg.text().starts_with('!') {
+                        return Ok(super::SteamEvent::Command {
+                            user: msg.user(),
+                            command: msg.text().to_string(),
+                        });
+                    }
+                    return Ok(super::SteamEvent::Comment {
+                        user: msg.user(),
+                        content: msg.text().to_string(),
+                    });
+                }
+                "USERNOTICE" => {
+                    if let Some(event) = msg.membership() {
+                        return Ok(event);
+                    }
+                }
+                "RECONNECT" => {
+                    log::info!("twitch asked to reconnect");
+                    self.reconnect().await?;
+                }
+                "NOTICE" => {
+                    log::warn!("twitch notice: {}", msg.text());
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl TwitchChat {
+    pub async fn from_config(config: crate::config::TwitchConfig) -> anyhow::Result<Self> {
+        Self::new(
+            &config.url,
+            &config.nick,
+            &config.token,
+            &config.channel,
+            config.max_retry,
+        )
+        .await
+    }
+}
+
+#[test]
+fn test_parse() {
+    let line = r"@badge-info=subscriber/8;badges=moderator/1,subscriber/6;bits=100;color=#0D4200;display-name=Ronni;emotes=;mod=1;room-id=1337;user-id=1337 :ronni!ronni@ronni.tmi.twitch.tv PRIVMSG #dallas :cheer100 hello\sworld";
+    let msg = IrcMessage::parse(line).unwrap();
+    assert_eq!(msg.command, "PRIVMSG");
+    assert_eq!(msg.nick(), Some("ronni"));
+    assert_eq!(msg.display_name(), "Ronni");
+    assert_eq!(msg.params, vec!["#dallas", "cheer100 hello\\sworld"]);
+    assert_eq!(msg.badges(), vec![("moderator", "1"), ("subscriber", "6")]);
+    assert_eq!(msg.bits(), 100);
+    assert!(msg.is_mod());
+
+    let msg = IrcMessage::parse(
+        "@system-msg=ronni\\shas\\ssubscribed! :tmi.twitch.tv USERNOTICE #dallas",
+    )
+    .unwrap();
+    assert_eq!(msg.tags["system-msg"], "ronni has subscribed!");
+
+    let msg = IrcMessage::parse(
+        "@display-name=Ronni;user-id=1337;msg-id=subgift;msg-param-recipient-display-name=Bob;msg-param-recipient-id=2;msg-param-sub-plan=1000 :tmi.twitch.tv USERNOTICE #dallas",
+    )
+    .unwrap();
+    assert_eq!(
+        msg.membership(),
+        Some(super::SteamEvent::Membership {
+            user: super::User::new(1337, "Ronni"),
+            level: "Tier 1 (赠送给 Bob)".to_string(),
+            months: 1,
+            amount: 4.99,
+            currency: "USD".to_string(),
+        })
+    );
+
+    let msg = IrcMessage::parse("PING :tmi.twitch.tv").unwrap();
+    assert_eq!(msg.command, "PING");
+    assert_eq!(msg.text(), "tmi.twitch.tv");
+}
+
+// Replays recorded IRC lines from a local WebSocket server.
+#[tokio::test]
+async fn test_replay() {
+    use crate::stream_platform::{SteamEvent, StreamPlatform, User};
+    use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+
+    async fn replay(mut socket: WebSocket, tx: tokio::sync::mpsc::UnboundedSender<String>) {
+        // PASS, NICK, JOIN and CAP arrive first
+        for _ in 0..4 {
+            if let Some(Ok(WsMessage::Text(line))) = socket.recv().await {
+                let _ = tx.send(line.to_string());
+            }
+        }
+        let recorded = [
+            ":tmi.twitch.tv 001 justinfan123 :Welcome, GLHF!\r\n:tmi.twitch.tv CAP * ACK :twitch.tv/tags twitch.tv/commands",
+            "PING :tmi.twitch.tv",
+            "@badges=moderator/1;display-name=Mod;mod=1;user-id=7 :mod!mod@mod.tmi.twitch.tv PRIVMSG #dallas :hi chat",
+            "@badges=moderator/1;display-name=Mod;mod=1;user-id=7 :mod!mod@mod.tmi.twitch.tv PRIVMSG #dallas :!topic cats",
+            "@badges=;bits=50;display-name= :viewer!viewer@viewer.tmi.twitch.tv PRIVMSG #dallas :cheer50 nice",
+            "@display-name=Ronni;login=ronni;msg-id=resub;msg-param-cumulative-months=6;msg-param-sub-plan=1000 :tmi.twitch.tv USERNOTICE #dallas :Great stream",
+        ];
+        for line in recorded {
+            let _ = socket.send(WsMessage::Text(line.into())).await;
+        }
+        while let Some(Ok(WsMessage::Text(line))) = socket.recv().await {
+            let _ = tx.send(line.to_string());
+        }
+    }
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let app = axum::Router::new().route(
+        "/",
+        axum::routing::any(move |ws: WebSocketUpgrade| {
+            let tx = tx.clone();
+            async move { ws.on_upgrade(move |socket| replay(socket, tx)) }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let mut chat = TwitchChat::new(&format!("ws://{addr}/"), "justinfan123", "", "#Dallas", 3)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        chat.next_event().await.unwrap(),
+        SteamEvent::Comment {
+            user: User::new(7, "Mod"),
+            content: "hi chat".to_string()
+        }
+    );
+    assert_eq!(
+        chat.next_event().await.unwrap(),
+        SteamEvent::Command {
+            user: User::new(7, "Mod"),
+            command: "!topic cats".to_string()
+        }
+    );
+    assert_eq!(
+        chat.next_event().await.unwrap(),
+        SteamEvent::SuperChat {
+            user: User::new("viewer", "viewer"),
+            content: "cheer50 nice".to_string(),
+            amount: 0.5,
+            currency: "USD".to_string(),
+        }
+    );
+    assert_eq!(
+        chat.next_event().await.unwrap(),
+        SteamEvent::Membership {
+            user: User::new("ronni", "Ronni"),
+            level: "Tier 1".to_string(),
+            months: 6,
+            amount: 4.99,
+            currency: "USD".to_string(),
+        }
+    );
+
+    let mut lines = vec![];
+    while lines.len() < 5 {
+        lines.push(rx.recv().await.unwrap());
+    }
+    assert_eq!(
+        lines,
+        vec![
+            "CAP REQ :twitch.tv/tags twitch.tv/commands",
+            "PASS SCHMOOPIIE",
+            "NICK justinfan123",
+            "JOIN #dallas",
+            "PONG :tmi.twitch.tv",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_reconnect() {
+    use crate::stream_platform::{SteamEvent, StreamPlatform, User};
+    use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    async fn serve(
+        mut socket: WebSocket,
+        n: usize,
+        tx: tokio::sync::mpsc::UnboundedSender<String>,
+    ) {
+        for _ in 0..4 {
+            if let Some(Ok(WsMessage::Text(line))) = socket.recv().await {
+                let _ = tx.send(line.to_string());
+            }
+        }
+        let say = format!(
+            "@display-name=Bob;user-id=2 :bob!bob@bob.tmi.twitch.tv PRIVMSG #dallas :hi {n}"
+        );
+        let _ = socket.send(WsMessage::Text(say.into())).await;
+        match n {
+            0 => {
+                let _ = socket
+                    .send(WsMessage::Text(":tmi.twitch.tv RECONNECT".into()))
+                    .await;
+            }
+            // hang up
+            1 => return,
+            _ => {}
+        }
+        while let Some(Ok(_)) = socket.recv().await {}
+    }
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let app = axum::Router::new().route(
+        "/",
+        axum::routing::any(move |ws: WebSocketUpgrade| {
+            let tx = tx.clone();
+            let n = connections.fetch_add(1, Ordering::SeqCst);
+            async move { ws.on_upgrade(move |socket| serve(socket, n, tx)) }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let mut chat = TwitchChat::new(&format!("ws://{addr}/"), "justinfan123", "", "dallas", 3)
+        .await
+        .unwrap();
+    chat.backoff_base = Duration::from_millis(10);
+
+    let hi = |n: usize| SteamEvent::Comment {
+        user: User::new(2, "Bob"),
+        content: format!("hi {n}"),
+    };
+    assert_eq!(chat.next_event().await.unwrap(), hi(0));
+    // RECONNECT is handled without an error, on a new connection
+    assert_eq!(chat.next_event().await.unwrap(), hi(1));
+    // and so is a closed connection
+    assert_eq!(chat.next_event().await.unwrap(), hi(2));
+
+    let mut joins = 0;
+    while let Ok(line) = rx.try_recv() {
+        joins += (line == "JOIN #dallas") as usize;
+    }
+    assert_eq!(joins, 3);
+}
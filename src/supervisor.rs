@@ -0,0 +1,253 @@
+//! Keeps the background tasks running, restarting them with backoff.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::config::SupervisorConfig;
+
+/// Exponential backoff capped at one minute, with up to 50% random jitter.
+pub fn backoff(attempt: u32, base: Duration) -> Duration {
+    use std::hash::{BuildHasher, Hasher};
+
+    let delay = base
+        .saturating_mul(1 << attempt.min(16))
+        .min(Duration::from_secs(60));
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    delay + delay.mul_f64((random % 1000) as f64 / 2000.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub enum TaskState {
+    Running,
+    /// Failed, waiting to be restarted.
+    Restarting,
+    /// Gave up after too many failures.
+    Failed,
+    /// Returned without an error, e.g. at shutdown.
+    Stopped,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct TaskHealth {
+    pub state: TaskState,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+}
+
+/// Runs tasks made by a factory again when they fail or panic, until they fail
+/// `max_restarts` times in a row. A task that ran for `stable_secs` starts
+/// counting from zero again. Tasks it gives up on are sent to the fatal channel.
+#[derive(Clone)]
+pub struct Supervisor {
+    config: SupervisorConfig,
+    health: Arc<Mutex<BTreeMap<String, TaskHealth>>>,
+    fatal: tokio::sync::mpsc::UnboundedSender<String>,
+}
+
+impl Supervisor {
+    pub fn new(config: SupervisorConfig) -> (Self, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let (fatal, fatal_rx) = tokio::sync::mpsc::unbounded_channel();
+        let supervisor = Self {
+            config,
+            health: Default::default(),
+            fatal,
+        };
+        (supervisor, fatal_rx)
+    }
+
+    pub fn health(&self) -> BTreeMap<String, TaskHealth> {
+        self.health.lock().unwrap().clone()
+    }
+
+    fn set_health<F: FnOnce(&mut TaskHealth)>(&self, name: &str, f: F) {
+        let mut health = self.health.lock().unwrap();
+        let health = health.entry(name.to_string()).or_insert(TaskHealth {
+            state: TaskState::Running,
+            restarts: 0,
+            last_error: None,
+        });
+        f(health);
+    }
+
+    pub fn spawn<F, Fut>(&self, name: &str, mut make: F) -> tokio::task::JoinHandle<()>
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        let name = name.to_string();
+        self.set_health(&name, |_| {});
+
+        tokio::spawn(async move {
+            let stable = Duration::from_secs(supervisor.config.stable_secs);
+            let base = Duration::from_millis(supervisor.config.backoff_base_ms);
+            let mut failures = 0;
+            loop {
+                supervisor.set_health(&name, |h| h.state = TaskState::Running);
+                let started = tokio::time::Instant::now();
+                // a panic only takes down this attempt, not the supervisor
+                let e = match tokio::spawn(make()).await {
+                    Ok(Ok(())) => {
+                        log::info!("task {} stopped", name);
+                        supervisor.set_health(&name, |h| h.state = TaskState::Stopped);
+                        return;
+                    }
+                    Ok(Err(e)) => e,
+                    Err(e) if e.is_panic() => anyhow::anyhow!("panicked: {}", panic_message(e)),
+                    Err(e) => {
+                        log::info!("task {} cancelled: {}", name, e);
+                        supervisor.set_health(&name, |h| h.state = TaskState::Stopped);
+                        return;
+                    }
+                };
+
+                if started.elapsed() >= stable {
+                    failures = 0;
+                }
+                failures += 1;
+                let error = format!("{:#}", e);
+                if failures > supervisor.config.max_restarts {
+                    log::error!(
+                        "task {} failed {} times, giving up: {}",
+                        name,
+                        failures,
+                        error
+                    );
+                    supervisor.set_health(&name, |h| {
+                        h.state = TaskState::Failed;
+                        h.last_error = Some(error);
+                    });
+                    let _ = supervisor.fatal.send(name);
+                    return;
+                }
+
+                let delay = backoff(failures - 1, base);
+                log::error!("task {} failed, restart in {:?}: {}", name, delay, error);
+                supervisor.set_health(&name, |h| {
+                    h.state = TaskState::Restarting;
+                    h.restarts += 1;
+                    h.last_error = Some(error);
+                });
+                tokio::time::sleep(delay).await;
+            }
+        })
+    }
+}
+
+fn panic_message(e: tokio::task::JoinError) -> String {
+    let panic = e.into_panic();
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown".to_string()
+    }
+}
+
+#[test]
+fn test_backoff() {
+    let base = Duration::from_secs(1);
+    for attempt in 0..4 {
+        let d = backoff(attempt, base);
+        assert!(d >= base * (1 << attempt) && d <= base * (1 << attempt) * 3 / 2);
+    }
+    assert!(backoff(30, base) <= Duration::from_secs(90));
+}
+
+#[tokio::test]
+async fn test_supervisor() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    let (supervisor, mut fatal) = Supervisor::new(SupervisorConfig {
+        max_restarts: 2,
+        stable_secs: 60,
+        backoff_base_ms: 1,
+    });
+
+    // fails twice, then stops
+    let runs = Arc::new(AtomicU32::new(0));
+    let runs_ = runs.clone();
+    supervisor
+        .spawn("flaky", move || {
+            let runs = runs_.clone();
+            async move {
+                match runs.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err(anyhow::anyhow!("boom")),
+                    _ => Ok(()),
+                }
+            }
+        })
+        .await
+        .unwrap();
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+    assert_eq!(
+        supervisor.health()["flaky"],
+        TaskHealth {
+            state: TaskState::Stopped,
+            restarts: 2,
+            last_error: Some("boom".to_string()),
+        }
+    );
+
+    // never recovers
+    supervisor
+        .spawn("broken", || async { Err(anyhow::anyhow!("down")) })
+        .await
+        .unwrap();
+    assert_eq!(fatal.recv().await.unwrap(), "broken");
+    let health = &supervisor.health()["broken"];
+    assert_eq!((health.state, health.restarts), (TaskState::Failed, 2));
+}
+
+#[tokio::test]
+async fn test_supervisor_panic() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    let (supervisor, mut fatal) = Supervisor::new(SupervisorConfig {
+        max_restarts: 2,
+        stable_secs: 60,
+        backoff_base_ms: 1,
+    });
+
+    // panics once, then stops
+    let runs = Arc::new(AtomicU32::new(0));
+    let runs_ = runs.clone();
+    supervisor
+        .spawn("panicky", move || {
+            let runs = runs_.clone();
+            async move {
+                if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                    panic!("oops {}", 1);
+                }
+                Ok(())
+            }
+        })
+        .await
+        .unwrap();
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+    assert_eq!(
+        supervisor.health()["panicky"],
+        TaskHealth {
+            state: TaskState::Stopped,
+            restarts: 1,
+            last_error: Some("panicked: oops 1".to_string()),
+        }
+    );
+
+    // panics count against the restart budget
+    supervisor
+        .spawn("doomed", || async { panic!("always") })
+        .await
+        .unwrap();
+    assert_eq!(fatal.recv().await.unwrap(), "doomed");
+    let health = &supervisor.health()["doomed"];
+    assert_eq!((health.state, health.restarts), (TaskState::Failed, 2));
+    assert_eq!(health.last_error.as_deref(), Some("panicked: always"));
+}
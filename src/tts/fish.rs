@@ -0,0 +1,44 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+
+use super::TtsEngine;
+use crate::config::TTSConfig;
+
+pub struct FishTts {
+    pub url: String,
+    pub api_key: String,
+}
+
+impl FishTts {
+    pub fn from_config(config: &TTSConfig) -> anyhow::Result<Arc<dyn TtsEngine>> {
+        match config {
+            TTSConfig::Fish(c) => Ok(Arc::new(Self {
+                url: c.base_url.clone(),
+                api_key: c.api_key.clone(),
+            })),
+            _ => Err(anyhow::anyhow!("not a fish tts config")),
+        }
+    }
+}
+
+impl TtsEngine for FishTts {
+    fn name(&self) -> &str {
+        "fish"
+    }
+
+    fn synthesize<'a>(
+        &'a self,
+        text: &'a str,
+        voice: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Bytes>> {
+        Box::pin(crate::llm::fish_tts(
+            &self.url,
+            &self.api_key,
+            voice,
+            text,
+            self.format(),
+        ))
+    }
+}
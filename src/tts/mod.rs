@@ -0,0 +1,198 @@
+use std::{collections::HashMap, sync::Arc};
+
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+
+use crate::config::TTSConfig;
+
+pub mod fish;
+pub mod openai;
+pub mod stable;
+
+pub trait TtsEngine: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Audio format `synthesize` returns, e.g. `wav`, `mp3`.
+    fn format(&self) -> &str {
+        "wav"
+    }
+
+    fn synthesize<'a>(
+        &'a self,
+        text: &'a str,
+        voice: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Bytes>>;
+}
+
+/// An engine together with the voice and avatar it speaks for.
+#[derive(Clone)]
+pub struct Tts {
+    pub engine: Arc<dyn TtsEngine>,
+    pub voice: String,
+    pub vtb_name: String,
+}
+
+impl Tts {
+    pub async fn synthesize(&self, text: &str) -> anyhow::Result<Bytes> {
+        self.engine.synthesize(text, &self.voice).await
+    }
+}
+
+/// MIME type of an audio format as named by `TtsEngine::format`.
+pub fn content_type(format: &str) -> &'static str {
+    match format {
+        "wav" => "audio/wav",
+        "mp3" => "audio/mpeg",
+        "opus" | "ogg" => "audio/ogg",
+        "aac" => "audio/aac",
+        "flac" => "audio/flac",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Length of a RIFF/WAVE clip, `None` for other formats.
+pub fn wav_duration(audio: &[u8]) -> Option<std::time::Duration> {
+    if audio.len() < 12 || &audio[0..4] != b"RIFF" || &audio[8..12] != b"WAVE" {
+        return None;
+    }
+
+    let mut byte_rate = None;
+    let mut chunks = &audio[12..];
+    while chunks.len() >= 8 {
+        let id = &chunks[0..4];
+        let size = u32::from_le_bytes(chunks[4..8].try_into().unwrap()) as usize;
+        let body = &chunks[8..];
+        match id {
+            b"fmt " if body.len() >= 12 => {
+                byte_rate = Some(u32::from_le_bytes(body[8..12].try_into().unwrap()));
+            }
+            // streamed wavs may not know their size, count what arrived
+            b"data" => {
+                let byte_rate = byte_rate.filter(|r| *r > 0)? as f64;
+                let len = size.min(body.len());
+                return Some(std::time::Duration::from_secs_f64(len as f64 / byte_rate));
+            }
+            _ => {}
+        }
+        // chunks are padded to an even size
+        chunks = body.get(size + size % 2..)?;
+    }
+    None
+}
+
+/// 16kHz mono 16 bit silence.
+pub fn silence_wav(secs: f64) -> Bytes {
+    const RATE: u32 = 16000;
+    let data_len = (secs * RATE as f64) as u32 * 2;
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend(b"RIFF");
+    wav.extend((36 + data_len).to_le_bytes());
+    wav.extend(b"WAVEfmt ");
+    wav.extend(16u32.to_le_bytes());
+    wav.extend(1u16.to_le_bytes());
+    wav.extend(1u16.to_le_bytes());
+    wav.extend(RATE.to_le_bytes());
+    wav.extend((RATE * 2).to_le_bytes());
+    wav.extend(2u16.to_le_bytes());
+    wav.extend(16u16.to_le_bytes());
+    wav.extend(b"data");
+    wav.extend(data_len.to_le_bytes());
+    wav.resize(44 + data_len as usize, 0);
+    Bytes::from(wav)
+}
+
+pub type TtsFactory = fn(&TTSConfig) -> anyhow::Result<Arc<dyn TtsEngine>>;
+
+/// Builds `TtsEngine`s from `TTSConfig`, keyed by its `platform` tag.
+pub struct TtsRegistry {
+    factories: HashMap<&'static str, TtsFactory>,
+}
+
+impl Default for TtsRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            factories: HashMap::new(),
+        };
+        registry.register("Stable", stable::StableTts::from_config);
+        registry.register("Fish", fish::FishTts::from_config);
+        registry.register("OpenAI", openai::OpenAITts::from_config);
+        registry
+    }
+}
+
+impl TtsRegistry {
+    pub fn register(&mut self, platform: &'static str, factory: TtsFactory) {
+        self.factories.insert(platform, factory);
+    }
+
+    pub fn build(&self, config: &TTSConfig) -> anyhow::Result<Tts> {
+        let factory = self
+            .factories
+            .get(config.platform())
+            .ok_or(anyhow::anyhow!(
+                "unknown tts platform {}",
+                config.platform()
+            ))?;
+        Ok(Tts {
+            engine: factory(config)?,
+            voice: config.speaker().to_string(),
+            vtb_name: config.vtb_name().to_string(),
+        })
+    }
+}
+
+#[test]
+fn test_wav_duration() {
+    // 16kHz mono 16 bit, half a second
+    let mut wav = b"RIFF\0\0\0\0WAVEfmt ".to_vec();
+    wav.extend(16u32.to_le_bytes());
+    wav.extend(1u16.to_le_bytes());
+    wav.extend(1u16.to_le_bytes());
+    wav.extend(16000u32.to_le_bytes());
+    wav.extend(32000u32.to_le_bytes());
+    wav.extend(2u16.to_le_bytes());
+    wav.extend(16u16.to_le_bytes());
+    wav.extend(b"data");
+    wav.extend(16000u32.to_le_bytes());
+    wav.extend(vec![0u8; 16000]);
+    assert_eq!(
+        wav_duration(&wav),
+        Some(std::time::Duration::from_millis(500))
+    );
+
+    assert_eq!(wav_duration(b"ID3\x04 not a wav"), None);
+}
+
+#[tokio::test]
+async fn test_registry() {
+    struct FakeTts;
+
+    impl TtsEngine for FakeTts {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        fn synthesize<'a>(
+            &'a self,
+            text: &'a str,
+            voice: &'a str,
+        ) -> BoxFuture<'a, anyhow::Result<Bytes>> {
+            Box::pin(async move { Ok(Bytes::from(format!("{voice}:{text}"))) })
+        }
+    }
+
+    let config = TTSConfig::Stable(crate::config::StableTTS {
+        base_url: "http://tts.com".to_string(),
+        speaker: "kelly".to_string(),
+        vtb_name: "miaomiao".to_string(),
+    });
+
+    let tts = TtsRegistry::default().build(&config).unwrap();
+    assert_eq!(tts.engine.name(), "stable");
+
+    let mut registry = TtsRegistry::default();
+    registry.register("Stable", |_| Ok(Arc::new(FakeTts)));
+    let tts = registry.build(&config).unwrap();
+    assert_eq!(tts.vtb_name, "miaomiao");
+    assert_eq!(tts.synthesize("hello").await.unwrap(), "kelly:hello");
+}
@@ -0,0 +1,99 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+
+use super::TtsEngine;
+use crate::config::TTSConfig;
+
+pub struct OpenAITts {
+    pub base_url: String,
+    pub api_key: String,
+    pub model: String,
+    pub response_format: String,
+    pub speed: Option<f32>,
+}
+
+impl OpenAITts {
+    pub fn from_config(config: &TTSConfig) -> anyhow::Result<Arc<dyn TtsEngine>> {
+        match config {
+            TTSConfig::OpenAI(c) => Ok(Arc::new(Self {
+                base_url: c.base_url.clone(),
+                api_key: c.api_key.clone().unwrap_or_default(),
+                model: c.model.clone(),
+                response_format: c.response_format.clone(),
+                speed: c.speed,
+            })),
+            _ => Err(anyhow::anyhow!("not an openai tts config")),
+        }
+    }
+}
+
+impl TtsEngine for OpenAITts {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    fn format(&self) -> &str {
+        &self.response_format
+    }
+
+    fn synthesize<'a>(
+        &'a self,
+        text: &'a str,
+        voice: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Bytes>> {
+        Box::pin(crate::llm::openai_tts(
+            &self.base_url,
+            &self.api_key,
+            &self.model,
+            voice,
+            text,
+            &self.response_format,
+            self.speed,
+        ))
+    }
+}
+
+#[tokio::test]
+async fn test_openai_tts() {
+    use axum::{http::HeaderMap, Json};
+
+    let app = axum::Router::new().route(
+        "/v1/audio/speech",
+        axum::routing::post(
+            |headers: HeaderMap, Json(body): Json<serde_json::Value>| async move {
+                assert_eq!(headers["authorization"], "Bearer sk-test");
+                assert_eq!(
+                    body,
+                    serde_json::json!({
+                        "model": "tts-1",
+                        "input": "hello",
+                        "voice": "alloy",
+                        "response_format": "mp3",
+                        "speed": 1.25,
+                    })
+                );
+                b"ID3".to_vec()
+            },
+        ),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let config = TTSConfig::OpenAI(crate::config::OpenAITTS {
+        base_url: format!("http://{addr}/v1/audio/speech"),
+        api_key: Some("sk-test".to_string()),
+        model: "tts-1".to_string(),
+        voice: "alloy".to_string(),
+        response_format: "mp3".to_string(),
+        speed: Some(1.25),
+        vtb_name: "vtb".to_string(),
+    });
+    let tts = super::TtsRegistry::default().build(&config).unwrap();
+    assert_eq!(tts.engine.name(), "openai");
+    assert_eq!(tts.engine.format(), "mp3");
+    assert_eq!(super::content_type(tts.engine.format()), "audio/mpeg");
+    assert_eq!(tts.synthesize("hello").await.unwrap(), "ID3");
+}
@@ -0,0 +1,36 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+
+use super::TtsEngine;
+use crate::config::TTSConfig;
+
+pub struct StableTts {
+    pub base_url: String,
+}
+
+impl StableTts {
+    pub fn from_config(config: &TTSConfig) -> anyhow::Result<Arc<dyn TtsEngine>> {
+        match config {
+            TTSConfig::Stable(c) => Ok(Arc::new(Self {
+                base_url: c.base_url.clone(),
+            })),
+            _ => Err(anyhow::anyhow!("not a stable tts config")),
+        }
+    }
+}
+
+impl TtsEngine for StableTts {
+    fn name(&self) -> &str {
+        "stable"
+    }
+
+    fn synthesize<'a>(
+        &'a self,
+        text: &'a str,
+        voice: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Bytes>> {
+        Box::pin(crate::llm::tts(&self.base_url, voice, text))
+    }
+}